@@ -10,9 +10,11 @@ ROOT_UUID="$1"
 EFI_PARTUUID="$2"
 if [ -z "${ROOT_UUID}" -o -z "${EFI_PARTUUID}" ]
 then
-    echo "$0 [root uuid] [efi partuuid]" >&2
+    echo "$0 [root uuid] [efi partuuid] [subvolumes...]" >&2
     exit 1
 fi
+shift 2
+SUBVOLUMES=("$@")
 
 export LC_ALL=C
 
@@ -126,9 +128,9 @@ efi /${EFI_DIR}/vmlinuz.efi
 EOF
 
 cat > /boot/efi/loader/entries/Pop_OS-old.conf <<EOF
-title Pop!_OS (@root.old)
+title Pop!_OS (@root.1)
 efi /${EFI_DIR}/vmlinuz.efi
-options ${CMDLINE} rootflags=subvol=@root.old
+options ${CMDLINE} rootflags=subvol=@root.1
 EOF
 
 cat > /boot/efi/loader/entries/Pop_OS-original.conf <<EOF
@@ -149,10 +151,11 @@ cat > /etc/fstab <<EOF
 #
 # NOTE: / is automatically mounted and does not require an entry
 PARTUUID=${EFI_PARTUUID}  /boot/efi  vfat  umask=0077  0  0
-UUID=${ROOT_UUID}  /home  btrfs  defaults,subvol=@home  0  0
-UUID=${ROOT_UUID}  /tmp  btrfs  defaults,subvol=@tmp  0  0
-UUID=${ROOT_UUID}  /var  btrfs  defaults,subvol=@var  0  0
 EOF
+for SUBVOLUME in "${SUBVOLUMES[@]}"
+do
+    echo "UUID=${ROOT_UUID}  /${SUBVOLUME}  btrfs  defaults,subvol=@${SUBVOLUME}  0  0" >> /etc/fstab
+done
 
 echo "Enabling kernelstub"
 sed -i 's/"live_mode": true,/"live_mode": false,/' /etc/kernelstub/configuration
@@ -1,9 +1,56 @@
-use std::process;
+use std::{env, io, path::PathBuf, process};
+
+fn main_inner() -> io::Result<()> {
+    let mut options = pop_core::BuildOptions::default();
+    let mut list = false;
+    let mut manifest_path = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} requires a value", arg),
+                )
+            })
+        };
+        match arg.as_str() {
+            "--cache-dir" => options.cache_dir = PathBuf::from(value()?),
+            "--force-from" => options.force_from = Some(value()?.parse()?),
+            "--list" => list = true,
+            "--output" => options.output = Some(PathBuf::from(value()?)),
+            "--stop" => options.stop = value()?.parse()?,
+            _ if manifest_path.is_none() && !arg.starts_with("--") => {
+                manifest_path = Some(arg);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unexpected argument {:?}", arg),
+                ))
+            }
+        }
+    }
+
+    // Without a manifest, the default desktop image is built
+    let manifest = match manifest_path {
+        Some(path) => pop_core::Manifest::load(path)?,
+        None => pop_core::Manifest::default(),
+    };
+
+    if list {
+        print!("{}", pop_core::cache_status(&manifest, &options)?);
+        return Ok(());
+    }
+
+    pop_core::build(&manifest, &options)
+}
 
 fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    match pop_core::build() {
+    match main_inner() {
         Ok(()) => (),
         Err(err) => {
             eprintln!("pop-core: error: {}", err);
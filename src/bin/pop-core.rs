@@ -1,26 +1,308 @@
-use std::{env, process};
+use std::{
+    env,
+    io::{self, Write},
+    process,
+};
 
-fn main() {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+fn run<I: Iterator<Item = String>>(args: I) -> io::Result<()> {
+    let mut options = pop_core::RunOptions::default();
+    let mut args = args.peekable();
+    while let Some(arg) = args.peek() {
+        match arg.as_str() {
+            "--" => {
+                args.next();
+                break;
+            }
+            "--force-commit" => options.force_commit = true,
+            "--ignore-low-space" => options.ignore_low_space = true,
+            "--keep-failed" => options.keep_failed = true,
+            "--wait" => options.wait = true,
+            _ if arg.starts_with("--") => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("run: unknown option {:?}", arg),
+                ))
+            }
+            _ => break,
+        }
+        args.next();
+    }
 
-    let mut args = env::args().skip(1);
     let command = match args.next() {
         Some(some) => some,
         None => match env::var("SHELL") {
             Ok(some) => some,
             //TODO: pull default shell from /etc/passwd?
             Err(_) => {
-                log::error!("no command provided and SHELL not set");
-                process::exit(1);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no command provided and SHELL not set",
+                ));
             }
         },
     };
 
-    match pop_core::run(command, args.collect()) {
+    pop_core::run(command, args.collect(), &options)
+}
+
+/// Asks the user to confirm `question` on the terminal, defaulting to no.
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
+
+fn factory_reset<I: Iterator<Item = String>>(args: I) -> io::Result<()> {
+    let mut options = pop_core::FactoryResetOptions::default();
+    let mut yes = false;
+    for arg in args {
+        match arg.as_str() {
+            "--reset-var" => options.reset_var = true,
+            "--wait" => options.wait = true,
+            "--wipe-home" => options.wipe_home = true,
+            "--yes" => yes = true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("factory-reset: unexpected argument {:?}", arg),
+                ))
+            }
+        }
+    }
+
+    if !yes {
+        let mut questions = vec![
+            "The next boot will use a copy of @root.original, without any packages installed since. Continue?",
+        ];
+        if options.reset_var {
+            questions.push("All system state in /var, such as logs, caches and containers, will be lost. Continue?");
+        }
+        if options.wipe_home {
+            questions
+                .push("ALL USER FILES in /home will be deleted and cannot be recovered. Continue?");
+        }
+        for question in questions {
+            if !confirm(question)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    "factory-reset: cancelled",
+                ));
+            }
+        }
+    }
+
+    pop_core::factory_reset(&options)
+}
+
+fn gc<I: Iterator<Item = String>>(mut args: I) -> io::Result<()> {
+    fn value<T: std::str::FromStr>(name: &str, value: Option<String>) -> io::Result<T> {
+        value
+            .as_deref()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("gc: {} requires a number", name),
+                )
+            })
+    }
+
+    let mut options = pop_core::GcOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--keep-days" => options.keep_days = Some(value(&arg, args.next())?),
+            "--keep-last" => options.keep_last = Some(value(&arg, args.next())?),
+            "--wait" => options.wait = true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("gc: unexpected argument {:?}", arg),
+                ))
+            }
+        }
+    }
+
+    print!("{}", pop_core::gc(&options)?);
+    Ok(())
+}
+
+fn inspect<I: Iterator<Item = String>>(args: I) -> io::Result<()> {
+    let mut args = args.peekable();
+    let mut wait = false;
+    if args.peek().map_or(false, |arg| arg == "--wait") {
+        wait = true;
+        args.next();
+    }
+    let target = match args.next() {
+        Some(arg) if arg.starts_with("--") => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("inspect: unexpected argument {:?}", arg),
+            ))
+        }
+        Some(some) => some,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "inspect: no generation provided",
+            ))
+        }
+    };
+    let mut command: Vec<String> = args.collect();
+    if command.first().map_or(false, |arg| arg == "--") {
+        command.remove(0);
+    }
+
+    pop_core::inspect(target, command, wait)
+}
+
+fn log<I: Iterator<Item = String>>(mut args: I) -> io::Result<()> {
+    let target = args.next();
+    if let Some(arg) = args.next() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("log: unexpected argument {:?}", arg),
+        ));
+    }
+
+    let data = pop_core::generation_log(target)?;
+    io::stdout().write_all(&data)
+}
+
+fn pin<I: Iterator<Item = String>>(name: &str, args: I, pinned: bool) -> io::Result<()> {
+    let mut target = None;
+    let mut wait = false;
+    for arg in args {
+        match arg.as_str() {
+            "--wait" => wait = true,
+            _ if target.is_none() && !arg.starts_with("--") => target = Some(arg),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: unexpected argument {:?}", name, arg),
+                ))
+            }
+        }
+    }
+    let target = target.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: no generation provided", name),
+        )
+    })?;
+
+    if pinned {
+        pop_core::pin(target, wait)
+    } else {
+        pop_core::unpin(target, wait)
+    }
+}
+
+fn rollback<I: Iterator<Item = String>>(args: I) -> io::Result<()> {
+    let mut target = None;
+    let mut wait = false;
+    for arg in args {
+        match arg.as_str() {
+            "--wait" => wait = true,
+            _ if target.is_none() && !arg.starts_with("--") => target = Some(arg),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("rollback: unexpected argument {:?}", arg),
+                ))
+            }
+        }
+    }
+
+    pop_core::rollback(target, wait)
+}
+
+fn diff<I: Iterator<Item = String>>(args: I) -> io::Result<()> {
+    let mut options = pop_core::DiffOptions::default();
+    let mut roots = Vec::new();
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--files" => options.files = true,
+            "--json" => json = true,
+            _ if roots.len() < 2 && !arg.starts_with("--") => roots.push(arg),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("diff: unexpected argument {:?}", arg),
+                ))
+            }
+        }
+    }
+    let mut roots = roots.into_iter();
+
+    let diff = pop_core::diff(roots.next(), roots.next(), &options)?;
+    if json {
+        let data = serde_json::to_string_pretty(&diff)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        println!("{}", data);
+    } else {
+        print!("{}", diff);
+    }
+    Ok(())
+}
+
+fn status<I: Iterator<Item = String>>(args: I) -> io::Result<()> {
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("status: unexpected argument {:?}", arg),
+                ))
+            }
+        }
+    }
+
+    let status = pop_core::status()?;
+    if json {
+        let data = serde_json::to_string_pretty(&status)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        println!("{}", data);
+    } else {
+        print!("{}", status);
+    }
+    Ok(())
+}
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut args = env::args().skip(1).peekable();
+    let res = match args.peek().map(|arg| arg.as_str()) {
+        Some("diff") => diff(args.skip(1)),
+        Some("factory-reset") => factory_reset(args.skip(1)),
+        Some("gc") => gc(args.skip(1)),
+        Some("inspect") => inspect(args.skip(1)),
+        Some("log") => log(args.skip(1)),
+        Some("pin") => pin("pin", args.skip(1), true),
+        Some("rollback") => rollback(args.skip(1)),
+        Some("status") => status(args.skip(1)),
+        Some("unpin") => pin("unpin", args.skip(1), false),
+        // Allows running commands with the same name as a subcommand
+        Some("run") => run(args.skip(1)),
+        _ => run(args),
+    };
+
+    match res {
         Ok(()) => (),
         Err(err) => {
             log::error!("{}", err);
-            process::exit(1);
+            // Pass on the exit code of a failed container command
+            let code = err
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<pop_core::CommandFailed>())
+                .map_or(1, |failed| failed.code());
+            process::exit(code);
         }
     }
 }
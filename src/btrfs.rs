@@ -0,0 +1,793 @@
+//! Btrfs subvolume operations, using the kernel ioctls directly instead of `btrfs-progs`.
+
+use std::{
+    error,
+    ffi::OsStr,
+    fmt,
+    fs::File,
+    io, mem,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+const BTRFS_IOCTL_MAGIC: u64 = 0x94;
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+const BTRFS_SUBVOL_NAME_MAX: usize = 4039;
+const BTRFS_INO_LOOKUP_PATH_MAX: usize = 4080;
+const BTRFS_VOL_NAME_MAX: usize = 255;
+const BTRFS_SEARCH_ARGS_BUFSIZE: usize = 4096 - mem::size_of::<SearchKey>();
+
+/// Read-only flag for subvolumes, used by snapshot creation and `SUBVOL_{GET,SET}FLAGS`.
+const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+
+/// The filesystem type reported by `statfs`.
+const BTRFS_SUPER_MAGIC: i64 = 0x9123_683e;
+
+/// The first inode number of every subvolume, which is its root directory.
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+const BTRFS_ROOT_TREE_DIR_OBJECTID: u64 = 6;
+const BTRFS_DIR_ITEM_KEY: u32 = 84;
+const BTRFS_EXTENT_DATA_KEY: u32 = 108;
+const BTRFS_QUOTA_TREE_OBJECTID: u64 = 8;
+const BTRFS_QGROUP_INFO_KEY: u32 = 242;
+
+const fn ioc(dir: u64, nr: u64, size: usize) -> u64 {
+    (dir << 30) | ((size as u64) << 16) | (BTRFS_IOCTL_MAGIC << 8) | nr
+}
+
+const fn iow(nr: u64, size: usize) -> u64 {
+    ioc(1, nr, size)
+}
+
+const fn ior(nr: u64, size: usize) -> u64 {
+    ioc(2, nr, size)
+}
+
+const fn iowr(nr: u64, size: usize) -> u64 {
+    ioc(3, nr, size)
+}
+
+const BTRFS_IOC_SNAP_CREATE_V2: u64 = iow(23, mem::size_of::<VolArgsV2>());
+const BTRFS_IOC_SUBVOL_CREATE: u64 = iow(14, mem::size_of::<VolArgs>());
+const BTRFS_IOC_SNAP_DESTROY: u64 = iow(15, mem::size_of::<VolArgs>());
+const BTRFS_IOC_SNAP_DESTROY_V2: u64 = iow(63, mem::size_of::<VolArgsV2>());
+const BTRFS_IOC_DEFAULT_SUBVOL: u64 = iow(19, mem::size_of::<u64>());
+const BTRFS_IOC_SUBVOL_GETFLAGS: u64 = ior(25, mem::size_of::<u64>());
+const BTRFS_IOC_SUBVOL_SETFLAGS: u64 = iow(26, mem::size_of::<u64>());
+const BTRFS_IOC_INO_LOOKUP: u64 = iowr(18, mem::size_of::<InoLookupArgs>());
+const BTRFS_IOC_TREE_SEARCH: u64 = iowr(17, mem::size_of::<SearchArgs>());
+const BTRFS_IOC_GET_SUBVOL_INFO: u64 = ior(60, mem::size_of::<GetSubvolInfoArgs>());
+const BTRFS_IOC_FS_INFO: u64 = ior(31, mem::size_of::<FsInfoArgs>());
+const BTRFS_IOC_SYNC: u64 = ioc(0, 8, 0);
+
+#[repr(C)]
+struct VolArgs {
+    fd: i64,
+    name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+
+#[repr(C)]
+struct VolArgsV2 {
+    fd: i64,
+    transid: u64,
+    flags: u64,
+    unused: [u64; 4],
+    name: [u8; BTRFS_SUBVOL_NAME_MAX + 1],
+}
+
+#[repr(C)]
+struct InoLookupArgs {
+    treeid: u64,
+    objectid: u64,
+    name: [u8; BTRFS_INO_LOOKUP_PATH_MAX],
+}
+
+/// The range of items to find with [`tree_search`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SearchKey {
+    pub tree_id: u64,
+    pub min_objectid: u64,
+    pub max_objectid: u64,
+    pub min_offset: u64,
+    pub max_offset: u64,
+    pub min_transid: u64,
+    pub max_transid: u64,
+    pub min_type: u32,
+    pub max_type: u32,
+    pub nr_items: u32,
+    unused: u32,
+    unused1: u64,
+    unused2: u64,
+    unused3: u64,
+    unused4: u64,
+}
+
+impl SearchKey {
+    /// Creates a key matching every item in the tree `tree_id`.
+    pub fn new(tree_id: u64) -> Self {
+        Self {
+            tree_id,
+            min_objectid: 0,
+            max_objectid: u64::MAX,
+            min_offset: 0,
+            max_offset: u64::MAX,
+            min_transid: 0,
+            max_transid: u64::MAX,
+            min_type: 0,
+            max_type: u32::from(u8::MAX),
+            nr_items: 0,
+            unused: 0,
+            unused1: 0,
+            unused2: 0,
+            unused3: 0,
+            unused4: 0,
+        }
+    }
+}
+
+#[repr(C)]
+struct SearchArgs {
+    key: SearchKey,
+    buf: [u8; BTRFS_SEARCH_ARGS_BUFSIZE],
+}
+
+#[repr(C)]
+struct SearchHeader {
+    transid: u64,
+    objectid: u64,
+    offset: u64,
+    kind: u32,
+    len: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Timespec {
+    sec: u64,
+    nsec: u32,
+}
+
+#[repr(C)]
+struct GetSubvolInfoArgs {
+    treeid: u64,
+    name: [u8; BTRFS_VOL_NAME_MAX + 1],
+    parent_id: u64,
+    dirid: u64,
+    generation: u64,
+    flags: u64,
+    uuid: [u8; 16],
+    parent_uuid: [u8; 16],
+    received_uuid: [u8; 16],
+    ctransid: u64,
+    otransid: u64,
+    stransid: u64,
+    rtransid: u64,
+    ctime: Timespec,
+    otime: Timespec,
+    stime: Timespec,
+    rtime: Timespec,
+    reserved: [u64; 8],
+}
+
+#[repr(C)]
+struct FsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    fsid: [u8; 16],
+    nodesize: u32,
+    sectorsize: u32,
+    clone_alignment: u32,
+    csum_type: u16,
+    csum_size: u16,
+    flags: u64,
+    generation: u64,
+    metadata_uuid: [u8; 16],
+    reserved: [u8; 944],
+}
+
+/// An error from a btrfs operation.
+#[derive(Debug)]
+pub enum Error {
+    /// A path could not be opened.
+    Open(PathBuf, io::Error),
+    /// An ioctl failed on a path.
+    Ioctl(&'static str, PathBuf, io::Error),
+    /// A path can not be used as a subvolume name, because it has no parent or file name, or
+    /// the name is too long.
+    InvalidName(PathBuf),
+    /// The kernel returned data that could not be parsed.
+    InvalidData(&'static str),
+    /// The filesystem of a path could not be found.
+    Stat(PathBuf, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Open(path, err) => write!(f, "failed to open {}: {}", path.display(), err),
+            Self::Ioctl(name, path, err) => {
+                write!(f, "{} failed on {}: {}", name, path.display(), err)
+            }
+            Self::InvalidName(path) => write!(f, "invalid subvolume name {}", path.display()),
+            Self::InvalidData(name) => write!(f, "{} returned invalid data", name),
+            Self::Stat(path, err) => write!(f, "failed to stat {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Open(_, err) | Self::Ioctl(_, _, err) | Self::Stat(_, err) => Some(err),
+            Self::InvalidName(_) | Self::InvalidData(_) => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        let kind = match &err {
+            Error::Open(_, err) | Error::Ioctl(_, _, err) | Error::Stat(_, err) => err.kind(),
+            Error::InvalidName(_) => io::ErrorKind::InvalidInput,
+            Error::InvalidData(_) => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn open(path: &Path) -> Result<File> {
+    File::open(path).map_err(|err| Error::Open(path.to_path_buf(), err))
+}
+
+/// Runs an ioctl on `file`, which was opened from `path`.
+///
+/// # Safety
+///
+/// `arg` must be the type the ioctl `request` expects.
+unsafe fn ioctl<T>(
+    file: &File,
+    path: &Path,
+    name: &'static str,
+    request: u64,
+    arg: *mut T,
+) -> Result<()> {
+    if libc::ioctl(file.as_raw_fd(), request as _, arg) < 0 {
+        Err(Error::Ioctl(
+            name,
+            path.to_path_buf(),
+            io::Error::last_os_error(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Splits `path` into its parent directory and a name that fits in `N` bytes with a nul.
+fn split_name<const N: usize>(path: &Path) -> Result<(&Path, [u8; N])> {
+    let invalid = || Error::InvalidName(path.to_path_buf());
+    let name = path.file_name().ok_or_else(invalid)?.as_bytes();
+    if name.len() >= N || name.contains(&0) {
+        return Err(invalid());
+    }
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return Err(invalid()),
+    };
+    let mut array = [0; N];
+    array[..name.len()].copy_from_slice(name);
+    Ok((parent, array))
+}
+
+/// Returns the ID of the subvolume containing `path`.
+pub fn subvolume_id<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    let file = open(path)?;
+    let mut args = InoLookupArgs {
+        treeid: 0,
+        objectid: BTRFS_FIRST_FREE_OBJECTID,
+        name: [0; BTRFS_INO_LOOKUP_PATH_MAX],
+    };
+    unsafe {
+        ioctl(
+            &file,
+            path,
+            "BTRFS_IOC_INO_LOOKUP",
+            BTRFS_IOC_INO_LOOKUP,
+            &mut args,
+        )?;
+    }
+    Ok(args.treeid)
+}
+
+/// Returns the path of `inode` relative to the root of the subvolume `tree_id`, using the
+/// filesystem `path` is on.
+pub fn inode_path<P: AsRef<Path>>(path: P, tree_id: u64, inode: u64) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let file = open(path)?;
+    let mut args = InoLookupArgs {
+        treeid: tree_id,
+        objectid: inode,
+        name: [0; BTRFS_INO_LOOKUP_PATH_MAX],
+    };
+    unsafe {
+        ioctl(
+            &file,
+            path,
+            "BTRFS_IOC_INO_LOOKUP",
+            BTRFS_IOC_INO_LOOKUP,
+            &mut args,
+        )?;
+    }
+    // The name is nul terminated, with a trailing slash for anything but the subvolume root
+    let len = args
+        .name
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(Error::InvalidData("BTRFS_IOC_INO_LOOKUP"))?;
+    let name = args.name[..len]
+        .strip_suffix(b"/")
+        .unwrap_or(&args.name[..len]);
+    Ok(PathBuf::from(OsStr::from_bytes(name)))
+}
+
+/// Returns the paths of files in the subvolume at `path` whose data was written after the
+/// transaction `generation`, relative to the subvolume root. This is the approach of
+/// `btrfs subvolume find-new`: only tree blocks newer than `generation` are searched, so
+/// unchanged parts of the tree are skipped. Deleted files and metadata-only changes are not
+/// found.
+pub fn find_new<P: AsRef<Path>>(path: P, generation: u64) -> Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let tree_id = subvolume_id(path)?;
+    let mut key = SearchKey::new(tree_id);
+    key.min_objectid = BTRFS_FIRST_FREE_OBJECTID;
+    key.min_type = BTRFS_EXTENT_DATA_KEY;
+    key.max_type = BTRFS_EXTENT_DATA_KEY;
+    key.min_transid = generation + 1;
+
+    let mut inodes = Vec::new();
+    for item in tree_search(path, key)? {
+        // Tree blocks are copied when any item in them changes, so also check the generation of
+        // the extent, which is the first field of struct btrfs_file_extent_item
+        if item.kind != BTRFS_EXTENT_DATA_KEY || item.data.len() < 8 {
+            continue;
+        }
+        let extent_generation = u64::from_le_bytes(item.data[..8].try_into().unwrap());
+        if extent_generation > generation {
+            inodes.push(item.objectid);
+        }
+    }
+    // Items are sorted by inode, and a file may have many extents
+    inodes.dedup();
+
+    let mut paths = Vec::with_capacity(inodes.len());
+    for inode in inodes {
+        match inode_path(path, tree_id, inode) {
+            Ok(ok) => paths.push(ok),
+            // Files that were deleted but are still open have no path
+            Err(Error::Ioctl(_, _, err)) if err.raw_os_error() == Some(libc::ENOENT) => (),
+            Err(err) => return Err(err),
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// An item found by [`tree_search`].
+#[derive(Debug)]
+pub struct SearchItem {
+    pub transid: u64,
+    pub objectid: u64,
+    pub offset: u64,
+    pub kind: u32,
+    pub data: Vec<u8>,
+}
+
+/// Finds every item matching `key` in a btrfs tree, using the filesystem `path` is on.
+pub fn tree_search<P: AsRef<Path>>(path: P, mut key: SearchKey) -> Result<Vec<SearchItem>> {
+    let path = path.as_ref();
+    let file = open(path)?;
+    let header_size = mem::size_of::<SearchHeader>();
+
+    let mut items = Vec::new();
+    loop {
+        let mut args = SearchArgs {
+            key: SearchKey {
+                nr_items: 4096,
+                ..key
+            },
+            buf: [0; BTRFS_SEARCH_ARGS_BUFSIZE],
+        };
+        unsafe {
+            ioctl(
+                &file,
+                path,
+                "BTRFS_IOC_TREE_SEARCH",
+                BTRFS_IOC_TREE_SEARCH,
+                &mut args,
+            )?;
+        }
+        if args.key.nr_items == 0 {
+            break;
+        }
+
+        let mut pos = 0;
+        let mut last = None;
+        for _ in 0..args.key.nr_items {
+            if pos + header_size > args.buf.len() {
+                return Err(Error::InvalidData("BTRFS_IOC_TREE_SEARCH"));
+            }
+            let header =
+                unsafe { (args.buf.as_ptr().add(pos) as *const SearchHeader).read_unaligned() };
+            pos += header_size;
+            let end = pos + header.len as usize;
+            if end > args.buf.len() {
+                return Err(Error::InvalidData("BTRFS_IOC_TREE_SEARCH"));
+            }
+            items.push(SearchItem {
+                transid: header.transid,
+                objectid: header.objectid,
+                offset: header.offset,
+                kind: header.kind,
+                data: args.buf[pos..end].to_vec(),
+            });
+            pos = end;
+            last = Some((header.objectid, header.kind, header.offset));
+        }
+
+        // Continue from the key after the last item found
+        let (objectid, kind, offset) = match last {
+            Some(some) => some,
+            None => break,
+        };
+        key.min_objectid = objectid;
+        key.min_type = kind;
+        if offset < u64::MAX {
+            key.min_offset = offset + 1;
+        } else if kind < u32::from(u8::MAX) {
+            key.min_type = kind + 1;
+            key.min_offset = 0;
+        } else if objectid < u64::MAX {
+            key.min_objectid = objectid + 1;
+            key.min_type = 0;
+            key.min_offset = 0;
+        } else {
+            break;
+        }
+        if (key.min_objectid, key.min_type, key.min_offset)
+            > (key.max_objectid, key.max_type, key.max_offset)
+        {
+            break;
+        }
+    }
+    Ok(items)
+}
+
+/// Returns the ID of the default subvolume of the filesystem `path` is on.
+pub fn default_subvolume_id<P: AsRef<Path>>(path: P) -> Result<u64> {
+    // The default subvolume is the location of the "default" item in the root tree directory
+    let mut key = SearchKey::new(BTRFS_ROOT_TREE_OBJECTID);
+    key.min_objectid = BTRFS_ROOT_TREE_DIR_OBJECTID;
+    key.max_objectid = BTRFS_ROOT_TREE_DIR_OBJECTID;
+    key.min_type = BTRFS_DIR_ITEM_KEY;
+    key.max_type = BTRFS_DIR_ITEM_KEY;
+    let items = tree_search(path, key)?;
+
+    // struct btrfs_dir_item is a 17 byte key, transid, data_len, name_len, and type
+    const DIR_ITEM_SIZE: usize = 30;
+    for item in items {
+        let mut data = &item.data[..];
+        while data.len() >= DIR_ITEM_SIZE {
+            let objectid = u64::from_le_bytes(data[..8].try_into().unwrap());
+            let data_len = u16::from_le_bytes(data[25..27].try_into().unwrap()) as usize;
+            let name_len = u16::from_le_bytes(data[27..29].try_into().unwrap()) as usize;
+            let end = DIR_ITEM_SIZE + name_len + data_len;
+            if data.len() < end {
+                break;
+            }
+            if &data[DIR_ITEM_SIZE..DIR_ITEM_SIZE + name_len] == b"default" {
+                return Ok(objectid);
+            }
+            data = &data[end..];
+        }
+    }
+    Err(Error::InvalidData("default subvolume search"))
+}
+
+/// Returns true if `path` is on a btrfs filesystem.
+pub fn is_btrfs<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let path = path.as_ref();
+    let file = open(path)?;
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::fstatfs(file.as_raw_fd(), &mut stat) } != 0 {
+        return Err(Error::Stat(path.to_path_buf(), io::Error::last_os_error()));
+    }
+    Ok(stat.f_type as i64 == BTRFS_SUPER_MAGIC)
+}
+
+/// Returns true if `path` is the root directory of a subvolume.
+pub fn is_subvolume<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let path = path.as_ref();
+    let metadata = path
+        .symlink_metadata()
+        .map_err(|err| Error::Stat(path.to_path_buf(), err))?;
+    Ok(metadata.is_dir() && metadata.ino() == BTRFS_FIRST_FREE_OBJECTID && is_btrfs(path)?)
+}
+
+/// Creates an empty subvolume at `path`.
+pub fn create_subvolume<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    let (parent, name) = split_name::<{ BTRFS_PATH_NAME_MAX + 1 }>(path)?;
+    let parent_file = open(parent)?;
+    let mut args = VolArgs { fd: 0, name };
+    unsafe {
+        ioctl(
+            &parent_file,
+            path,
+            "BTRFS_IOC_SUBVOL_CREATE",
+            BTRFS_IOC_SUBVOL_CREATE,
+            &mut args,
+        )
+    }
+}
+
+/// Creates a snapshot of the subvolume `source` at `dest`, which is read-only if `readonly` is
+/// set.
+pub fn snapshot<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q, readonly: bool) -> Result<()> {
+    let source = source.as_ref();
+    let dest = dest.as_ref();
+    let source_file = open(source)?;
+    let (parent, name) = split_name::<{ BTRFS_SUBVOL_NAME_MAX + 1 }>(dest)?;
+    let parent_file = open(parent)?;
+    let mut args = VolArgsV2 {
+        fd: i64::from(source_file.as_raw_fd()),
+        transid: 0,
+        flags: if readonly { BTRFS_SUBVOL_RDONLY } else { 0 },
+        unused: [0; 4],
+        name,
+    };
+    unsafe {
+        ioctl(
+            &parent_file,
+            dest,
+            "BTRFS_IOC_SNAP_CREATE_V2",
+            BTRFS_IOC_SNAP_CREATE_V2,
+            &mut args,
+        )
+    }
+}
+
+/// Deletes the subvolume at `path`, which may be read-only.
+pub fn delete_subvolume<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    let (parent, name) = split_name::<{ BTRFS_SUBVOL_NAME_MAX + 1 }>(path)?;
+    let parent_file = open(parent)?;
+    let mut args = VolArgsV2 {
+        fd: 0,
+        transid: 0,
+        flags: 0,
+        unused: [0; 4],
+        name,
+    };
+    let res = unsafe {
+        ioctl(
+            &parent_file,
+            path,
+            "BTRFS_IOC_SNAP_DESTROY_V2",
+            BTRFS_IOC_SNAP_DESTROY_V2,
+            &mut args,
+        )
+    };
+    match res {
+        // Kernels before 5.7 only have the original ioctl
+        Err(Error::Ioctl(_, _, err)) if err.raw_os_error() == Some(libc::ENOTTY) => {
+            let (_, name) = split_name::<{ BTRFS_PATH_NAME_MAX + 1 }>(path)?;
+            let mut args = VolArgs { fd: 0, name };
+            unsafe {
+                ioctl(
+                    &parent_file,
+                    path,
+                    "BTRFS_IOC_SNAP_DESTROY",
+                    BTRFS_IOC_SNAP_DESTROY,
+                    &mut args,
+                )
+            }
+        }
+        res => res,
+    }
+}
+
+fn subvolume_flags(file: &File, path: &Path) -> Result<u64> {
+    let mut flags = 0u64;
+    unsafe {
+        ioctl(
+            file,
+            path,
+            "BTRFS_IOC_SUBVOL_GETFLAGS",
+            BTRFS_IOC_SUBVOL_GETFLAGS,
+            &mut flags,
+        )?;
+    }
+    Ok(flags)
+}
+
+/// Returns true if the subvolume at `path` is read-only.
+pub fn is_readonly<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let path = path.as_ref();
+    let file = open(path)?;
+    Ok(subvolume_flags(&file, path)? & BTRFS_SUBVOL_RDONLY != 0)
+}
+
+/// Makes the subvolume at `path` read-only or writable.
+pub fn set_readonly<P: AsRef<Path>>(path: P, readonly: bool) -> Result<()> {
+    let path = path.as_ref();
+    let file = open(path)?;
+    let mut flags = subvolume_flags(&file, path)?;
+    if readonly {
+        flags |= BTRFS_SUBVOL_RDONLY;
+    } else {
+        flags &= !BTRFS_SUBVOL_RDONLY;
+    }
+    unsafe {
+        ioctl(
+            &file,
+            path,
+            "BTRFS_IOC_SUBVOL_SETFLAGS",
+            BTRFS_IOC_SUBVOL_SETFLAGS,
+            &mut flags,
+        )
+    }
+}
+
+/// Makes the subvolume at `path` the default subvolume of its filesystem.
+pub fn set_default<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    let mut id = subvolume_id(path)?;
+    let file = open(path)?;
+    unsafe {
+        ioctl(
+            &file,
+            path,
+            "BTRFS_IOC_DEFAULT_SUBVOL",
+            BTRFS_IOC_DEFAULT_SUBVOL,
+            &mut id,
+        )
+    }
+}
+
+/// Returns the UUID of the filesystem `path` is on, as shown by `blkid`.
+pub fn filesystem_uuid<P: AsRef<Path>>(path: P) -> Result<[u8; 16]> {
+    let path = path.as_ref();
+    let file = open(path)?;
+    let mut args: FsInfoArgs = unsafe { mem::zeroed() };
+    unsafe {
+        ioctl(
+            &file,
+            path,
+            "BTRFS_IOC_FS_INFO",
+            BTRFS_IOC_FS_INFO,
+            &mut args,
+        )?;
+    }
+    Ok(args.fsid)
+}
+
+/// Returns the space used only by the subvolume `subvolid`, which deleting it would free, from
+/// its level 0 qgroup. Returns `None` if quotas are not enabled on the filesystem `path` is on.
+pub fn qgroup_exclusive<P: AsRef<Path>>(path: P, subvolid: u64) -> Result<Option<u64>> {
+    let mut key = SearchKey::new(BTRFS_QUOTA_TREE_OBJECTID);
+    key.min_objectid = 0;
+    key.max_objectid = 0;
+    key.min_type = BTRFS_QGROUP_INFO_KEY;
+    key.max_type = BTRFS_QGROUP_INFO_KEY;
+    key.min_offset = subvolid;
+    key.max_offset = subvolid;
+    let items = match tree_search(path, key) {
+        Ok(ok) => ok,
+        // The quota tree does not exist if quotas were never enabled
+        Err(Error::Ioctl(_, _, err)) if err.raw_os_error() == Some(libc::ENOENT) => {
+            return Ok(None)
+        }
+        Err(err) => return Err(err),
+    };
+    // struct btrfs_qgroup_info_item is generation, rfer, rfer_cmpr, excl, and excl_cmpr
+    for item in items {
+        if item.kind == BTRFS_QGROUP_INFO_KEY && item.offset == subvolid && item.data.len() >= 32 {
+            return Ok(Some(u64::from_le_bytes(
+                item.data[24..32].try_into().unwrap(),
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Commits the current transaction of the filesystem `path` is on, so that subvolume details
+/// such as the generation are up to date.
+pub fn sync<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    let file = open(path)?;
+    unsafe {
+        ioctl::<u8>(
+            &file,
+            path,
+            "BTRFS_IOC_SYNC",
+            BTRFS_IOC_SYNC,
+            std::ptr::null_mut(),
+        )
+    }
+}
+
+/// Details about a subvolume.
+#[derive(Clone, Debug)]
+pub struct SubvolumeInfo {
+    /// The subvolume ID.
+    pub id: u64,
+    /// The generation the subvolume was last changed in.
+    pub generation: u64,
+    /// The creation time, in seconds since the epoch.
+    pub created: Option<u64>,
+    pub readonly: bool,
+    pub uuid: [u8; 16],
+    /// The UUID of the subvolume this is a snapshot of, or all zeros if it is not a snapshot.
+    pub parent_uuid: [u8; 16],
+    /// The transaction the last inode in the subvolume was changed in.
+    pub ctransid: u64,
+    /// The transaction the subvolume was created in, which never changes.
+    pub otransid: u64,
+}
+
+/// Returns details about the subvolume at `path`.
+pub fn subvolume_info<P: AsRef<Path>>(path: P) -> Result<SubvolumeInfo> {
+    let path = path.as_ref();
+    let file = open(path)?;
+    let mut args: GetSubvolInfoArgs = unsafe { mem::zeroed() };
+    unsafe {
+        ioctl(
+            &file,
+            path,
+            "BTRFS_IOC_GET_SUBVOL_INFO",
+            BTRFS_IOC_GET_SUBVOL_INFO,
+            &mut args,
+        )?;
+    }
+    Ok(SubvolumeInfo {
+        id: args.treeid,
+        generation: args.generation,
+        created: if args.otime.sec == 0 {
+            None
+        } else {
+            Some(args.otime.sec)
+        },
+        readonly: args.flags & BTRFS_SUBVOL_RDONLY != 0,
+        uuid: args.uuid,
+        parent_uuid: args.parent_uuid,
+        ctransid: args.ctransid,
+        otransid: args.otransid,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values from the kernel's uapi/linux/btrfs.h, which encode the size of each argument
+    #[test]
+    fn ioctl_numbers() {
+        assert_eq!(BTRFS_IOC_SNAP_CREATE_V2, 0x5000_9417);
+        assert_eq!(BTRFS_IOC_SUBVOL_CREATE, 0x5000_940e);
+        assert_eq!(BTRFS_IOC_SNAP_DESTROY, 0x5000_940f);
+        assert_eq!(BTRFS_IOC_SNAP_DESTROY_V2, 0x5000_943f);
+        assert_eq!(BTRFS_IOC_DEFAULT_SUBVOL, 0x4008_9413);
+        assert_eq!(BTRFS_IOC_SUBVOL_GETFLAGS, 0x8008_9419);
+        assert_eq!(BTRFS_IOC_SUBVOL_SETFLAGS, 0x4008_941a);
+        assert_eq!(BTRFS_IOC_INO_LOOKUP, 0xd000_9412);
+        assert_eq!(BTRFS_IOC_TREE_SEARCH, 0xd000_9411);
+        assert_eq!(BTRFS_IOC_GET_SUBVOL_INFO, 0x81f8_943c);
+        assert_eq!(BTRFS_IOC_FS_INFO, 0x8400_941f);
+        assert_eq!(BTRFS_IOC_SYNC, 0x9408);
+    }
+}
@@ -1,83 +1,20 @@
 use std::{
-    fs, io,
-    path::Path,
-    process::{Command, Stdio},
-    str,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
 };
 
 use crate::{
-    util::{check_output, check_status},
-    Cache, Debootstrap, Loopback, Mount,
+    btrfs, filesystem_uuid, generation_name, partition_uuid, util::check_status, Cache,
+    Debootstrap, InputHasher, Loopback, Manifest, Mount, PackageStage,
 };
 
-const SERVER_PACKAGES: &'static [&'static str] = &[
-    "binutils", // for unified kernel image
-    "btrfs-progs",
-    "kernelstub",
-    "linux-system76",
-    "network-manager",
-    "pop-default-settings",
-    "shim-signed", // for secure boot
-    "systemd-container",
-];
-
-const DESKTOP_PACKAGES: &'static [&'static str] = &[
-    "alacritty",
-    "cosmic-session",
-    "flatpak",
-    "libegl1",         // cosmic-comp dependency
-    "libgl1-mesa-dri", // cosmic-comp dependency
-    "libglib2.0-bin",  // for gsettings command
-    "pop-gtk-theme",
-    "pop-icon-theme",
-    "pop-wallpapers",
-    "wireplumber",
-];
-
-fn server(root_dir: &Path) -> io::Result<()> {
-    log::info!("Resetting hostname");
-    fs::write(
-        root_dir.join("etc/hostname"),
-        include_bytes!("../res/etc/hostname"),
-    )?;
-
-    log::info!("Resetting Pop!_OS System APT repository");
-    fs::write(
-        root_dir.join("etc/apt/sources.list"),
-        include_bytes!("../res/etc/apt/sources.list"),
-    )?;
-    fs::write(
-        root_dir.join("etc/apt/sources.list.d/system.sources"),
-        include_bytes!("../res/etc/apt/sources.list.d/system.sources"),
-    )?;
-
-    log::info!("Adding Pop!_OS Release APT repository");
-    fs::write(
-        root_dir.join("etc/apt/sources.list.d/pop-os-release.sources"),
-        include_bytes!("../res/etc/apt/sources.list.d/pop-os-release.sources"),
-    )?;
-    fs::write(
-        root_dir.join("etc/apt/trusted.gpg.d/pop-keyring-2017-archive.gpg"),
-        include_bytes!("../res/etc/apt/trusted.gpg.d/pop-keyring-2017-archive.gpg"),
-    )?;
-
-    log::info!("Adding Pop!_OS Applications APT repository");
-    fs::write(
-        root_dir.join("etc/apt/sources.list.d/pop-os-apps.sources"),
-        include_bytes!("../res/etc/apt/sources.list.d/pop-os-release.sources"),
-    )?;
-
-    let kernelstub_dir = root_dir.join("etc/kernelstub");
-    if !kernelstub_dir.exists() {
-        log::info!("Creating kernelstub configuration directory");
-        fs::create_dir(&kernelstub_dir)?;
-    }
-    log::info!("Creating kernelstub configuration file");
-    fs::write(
-        kernelstub_dir.join("configuration"),
-        include_bytes!("../res/etc/kernelstub/configuration"),
-    )?;
+/// The pop-core binary installed in the image.
+const POP_CORE_BINARY: &str = "target/release/pop-core";
 
+/// Runs the apt script in `root_dir`, which upgrades and installs `packages`.
+fn apt(root_dir: &Path, packages: &[String]) -> io::Result<()> {
     log::info!("Copying apt script");
     fs::write(root_dir.join("apt.sh"), include_bytes!("../res/apt.sh"))?;
 
@@ -86,10 +23,10 @@ fn server(root_dir: &Path) -> io::Result<()> {
         .arg("--machine=pop-core-install")
         .arg("--resolv-conf=replace-host")
         .arg("-D")
-        .arg(&root_dir)
+        .arg(root_dir)
         .arg("bash")
         .arg("/apt.sh")
-        .args(SERVER_PACKAGES)
+        .args(packages)
         .status()
         .and_then(check_status)?;
 
@@ -99,33 +36,25 @@ fn server(root_dir: &Path) -> io::Result<()> {
     Ok(())
 }
 
-fn desktop(root_dir: &Path) -> io::Result<()> {
-    log::info!("Copying apt script");
-    fs::write(root_dir.join("apt.sh"), include_bytes!("../res/apt.sh"))?;
-
-    log::info!("Running apt script");
-    Command::new("systemd-nspawn")
-        .arg("--machine=pop-core-install")
-        .arg("--resolv-conf=replace-host")
-        .arg("-D")
-        .arg(&root_dir)
-        .arg("bash")
-        .arg("/apt.sh")
-        .args(SERVER_PACKAGES)
-        .args(DESKTOP_PACKAGES)
-        .status()
-        .and_then(check_status)?;
-
-    log::info!("Removing apt script");
-    fs::remove_file(root_dir.join("apt.sh"))?;
+fn server(root_dir: &Path, manifest: &Manifest) -> io::Result<()> {
+    manifest.server.write_files(root_dir, &manifest.base_dir)?;
+    apt(root_dir, &manifest.server_packages())
+}
 
-    Ok(())
+fn desktop(root_dir: &Path, manifest: &Manifest) -> io::Result<()> {
+    manifest.desktop.write_files(root_dir, &manifest.base_dir)?;
+    apt(root_dir, &manifest.desktop_packages())
 }
 
-fn image(root_dir: &Path, root_uuid: &str, efi_partuuid: &str) -> io::Result<()> {
+fn image(
+    root_dir: &Path,
+    root_uuid: &str,
+    efi_partuuid: &str,
+    subvolumes: &[String],
+) -> io::Result<()> {
     //TODO: use package for this
     log::info!("Copying pop-core binary");
-    fs::copy("target/release/pop-core", root_dir.join("usr/bin/pop-core"))?;
+    fs::copy(POP_CORE_BINARY, root_dir.join("usr/bin/pop-core"))?;
 
     log::info!("Copying image script");
     fs::write(root_dir.join("image.sh"), include_bytes!("../res/image.sh"))?;
@@ -134,11 +63,12 @@ fn image(root_dir: &Path, root_uuid: &str, efi_partuuid: &str) -> io::Result<()>
     Command::new("systemd-nspawn")
         .arg("--machine=pop-core-install")
         .arg("-D")
-        .arg(&root_dir)
+        .arg(root_dir)
         .arg("bash")
         .arg("/image.sh")
         .arg(root_uuid)
         .arg(efi_partuuid)
+        .args(subvolumes)
         .status()
         .and_then(check_status)?;
 
@@ -148,199 +78,413 @@ fn image(root_dir: &Path, root_uuid: &str, efi_partuuid: &str) -> io::Result<()>
     Ok(())
 }
 
-pub fn build() -> io::Result<()> {
-    //TODO: ensure there are no active mounts inside any of the partial directories before removal!
-    let mut cache = Cache::new("build/cache", |name| {
-        ["debootstrap", "desktop", "image", "server"].contains(&name)
-    })?;
+/// A stage of the image build, in the order they are built. Each stage is cached in a directory
+/// of the same name.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Stage {
+    Debootstrap,
+    Server,
+    Desktop,
+    Image,
+}
 
-    let (debootstrap_dir, debootstrap_rebuilt) =
-        cache.build("debootstrap", false, |partial_dir| {
-            log::info!("Creating debootstrap");
-            Debootstrap::new(&partial_dir).variant("minbase").run()?;
-            Ok(())
-        })?;
+impl Stage {
+    pub const ALL: [Self; 4] = [Self::Debootstrap, Self::Server, Self::Desktop, Self::Image];
 
-    let (server_dir, server_rebuilt) =
-        cache.build("server", debootstrap_rebuilt, |partial_dir| {
-            log::info!("Copying debootstrap files");
-            Command::new("cp")
-                .arg("--archive")
-                .arg("--no-target-directory")
-                .arg(&debootstrap_dir)
-                .arg(&partial_dir)
-                .status()
-                .and_then(check_status)?;
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Debootstrap => "debootstrap",
+            Self::Server => "server",
+            Self::Desktop => "desktop",
+            Self::Image => "image",
+        }
+    }
+}
 
-            server(&partial_dir)
-        })?;
+impl FromStr for Stage {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> io::Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|stage| stage.name() == name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "unknown stage {:?}, expected one of debootstrap, server, desktop, image",
+                        name
+                    ),
+                )
+            })
+    }
+}
 
-    let (desktop_dir, desktop_rebuilt) = cache.build("desktop", server_rebuilt, |partial_dir| {
-        log::info!("Copying server files");
-        Command::new("cp")
-            .arg("--archive")
-            .arg("--no-target-directory")
-            .arg(&server_dir)
-            .arg(&partial_dir)
-            .status()
-            .and_then(check_status)?;
+/// Options for [`build`].
+#[derive(Debug)]
+pub struct BuildOptions {
+    /// Directory holding a subdirectory for each cached stage.
+    pub cache_dir: PathBuf,
+    /// The last stage to build.
+    pub stop: Stage,
+    /// Rebuild this stage even if it is cached. Later stages are rebuilt as they depend on it.
+    pub force_from: Option<Stage>,
+    /// Copy the image to this path once built.
+    pub output: Option<PathBuf>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            cache_dir: PathBuf::from("build/cache"),
+            stop: Stage::Image,
+            force_from: None,
+            output: None,
+        }
+    }
+}
+
+/// Whether a stage in the cache can be used as is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StageState {
+    Cached,
+    /// Cached, but will be rebuilt as its inputs or a stage it depends on changed, or as it was
+    /// forced to be.
+    Stale,
+    Missing,
+    /// An interrupted build left partial data, which the next build will delete.
+    Partial,
+}
+
+impl StageState {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Cached => "cached",
+            Self::Stale => "stale",
+            Self::Missing => "missing",
+            Self::Partial => "partial",
+        }
+    }
+}
+
+/// The state of every stage in a cache, as listed by `pop-core-build --list`.
+#[derive(Debug)]
+pub struct CacheStatus {
+    pub stages: Vec<(Stage, StageState)>,
+}
+
+impl fmt::Display for CacheStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<12} STATE", "STAGE")?;
+        for (stage, state) in &self.stages {
+            writeln!(f, "{:<12} {}", stage.name(), state.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// Adds the repositories, overlays and packages of a stage to `hasher`.
+fn hash_package_stage(
+    hasher: &mut InputHasher,
+    manifest: &Manifest,
+    stage: &PackageStage,
+    packages: &[String],
+) -> io::Result<()> {
+    hasher.input("apt.sh", include_bytes!("../res/apt.sh"));
+    for repository in &stage.repositories {
+        hasher.input(
+            &repository.name,
+            &repository.overlay().data(&manifest.base_dir)?,
+        );
+    }
+    for overlay in &stage.overlays {
+        hasher.input(
+            &overlay.path.display().to_string(),
+            &overlay.data(&manifest.base_dir)?,
+        );
+    }
+    hasher.input("packages", packages.join(" ").as_bytes());
+    Ok(())
+}
+
+/// Returns a hash of the inputs of each stage, in the order of [`Stage::ALL`]. Each includes the
+/// hash of the stage before it, so a changed stage also invalidates the stages after it.
+fn stage_hashes(manifest: &Manifest) -> io::Result<Vec<String>> {
+    let mut hashes: Vec<String> = Vec::new();
+    for stage in Stage::ALL {
+        let mut hasher = InputHasher::new();
+        hasher.input("version", env!("CARGO_PKG_VERSION").as_bytes());
+        if let Some(parent) = hashes.last() {
+            hasher.input("parent", parent.as_bytes());
+        }
+        match stage {
+            Stage::Debootstrap => {
+                let config = &manifest.debootstrap;
+                hasher
+                    .input("suite", config.suite.as_bytes())
+                    .input("arch", config.arch.as_bytes())
+                    .input("mirror", config.mirror.as_bytes())
+                    .input(
+                        "variant",
+                        config.variant.as_deref().unwrap_or_default().as_bytes(),
+                    )
+                    .input("include", config.include.join(",").as_bytes())
+                    .input("exclude", config.exclude.join(",").as_bytes());
+            }
+            Stage::Server => {
+                hash_package_stage(
+                    &mut hasher,
+                    manifest,
+                    &manifest.server,
+                    &manifest.server_packages(),
+                )?;
+            }
+            Stage::Desktop => {
+                hash_package_stage(
+                    &mut hasher,
+                    manifest,
+                    &manifest.desktop,
+                    &manifest.desktop_packages(),
+                )?;
+            }
+            Stage::Image => {
+                hasher
+                    .input("image.sh", include_bytes!("../res/image.sh"))
+                    .input("size", manifest.image.size.as_bytes())
+                    .input("esp-size", manifest.image.esp_size.as_bytes())
+                    .input("subvolumes", manifest.image.subvolumes.join(" ").as_bytes());
+                // Copied into the image, and missing until built
+                hasher.input_file("pop-core", Path::new(POP_CORE_BINARY))?;
+            }
+        }
+        hashes.push(hasher.finish());
+    }
+    Ok(hashes)
+}
 
-        desktop(&partial_dir)
+/// Returns the state of every stage in the cache for building `manifest` with `options`,
+/// without changing the cache. Stages `options` forces to be rebuilt are stale.
+pub fn cache_status(manifest: &Manifest, options: &BuildOptions) -> io::Result<CacheStatus> {
+    let cache_dir = &options.cache_dir;
+    let hashes = stage_hashes(manifest)?;
+    let mut stages = Vec::new();
+    let mut rebuilt = false;
+    for (stage, hash) in Stage::ALL.iter().copied().zip(hashes.iter()) {
+        let stage_dir = cache_dir.join(stage.name());
+        let current = fs::read_to_string(cache_dir.join(format!("{}.hash", stage.name())))
+            .map_or(false, |data| &data == hash);
+        let state = if cache_dir.join(format!("partial.{}", stage.name())).exists() {
+            StageState::Partial
+        } else if !stage_dir.exists() {
+            StageState::Missing
+        } else if rebuilt || !current || options.force_from.map_or(false, |from| stage >= from) {
+            StageState::Stale
+        } else {
+            StageState::Cached
+        };
+        rebuilt = state != StageState::Cached;
+        stages.push((stage, state));
+    }
+    Ok(CacheStatus { stages })
+}
+
+/// Builds the image described by `manifest`, which must have been validated, up to the stage
+/// `options.stop`. The image is `image/image.raw` in the cache directory.
+pub fn build(manifest: &Manifest, options: &BuildOptions) -> io::Result<()> {
+    let (image_size, esp_size) = manifest.image_sizes()?;
+    if options.output.is_some() && options.stop != Stage::Image {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "an output path requires building the image stage",
+        ));
+    }
+    let force = |stage: Stage| options.force_from.map_or(false, |from| stage >= from);
+    let done = |stage: Stage| {
+        if stage == options.stop {
+            log::info!("Stopping after {} stage", stage.name());
+            true
+        } else {
+            false
+        }
+    };
+
+    let hashes = stage_hashes(manifest)?;
+    let hash = |stage: Stage| hashes[stage as usize].as_str();
+
+    let mut cache = Cache::new(&options.cache_dir, |name| {
+        let name = name.strip_suffix(".hash").unwrap_or(name);
+        Stage::ALL.iter().any(|stage| stage.name() == name)
     })?;
 
-    let (image_dir, image_rebuilt) = cache.build("image", desktop_rebuilt, |partial_dir| {
-        fs::create_dir(&partial_dir)?;
+    let (debootstrap_dir, debootstrap_rebuilt) = cache.build_dir(
+        "debootstrap",
+        hash(Stage::Debootstrap),
+        force(Stage::Debootstrap),
+        |partial_dir| {
+            log::info!("Creating debootstrap");
+            let config = &manifest.debootstrap;
+            let mut debootstrap = Debootstrap::new(partial_dir)
+                .suite(&config.suite)
+                .arch(&config.arch)
+                .mirror(&config.mirror);
+            if let Some(variant) = &config.variant {
+                debootstrap = debootstrap.variant(variant);
+            }
+            for package in &config.include {
+                debootstrap = debootstrap.include_package(package);
+            }
+            for package in &config.exclude {
+                debootstrap = debootstrap.exclude_package(package);
+            }
+            debootstrap.run()?;
+            Ok(())
+        },
+    )?;
+    if done(Stage::Debootstrap) {
+        return Ok(());
+    }
 
-        //TODO: move logic to Rust as much as possible
+    let (server_dir, server_rebuilt) = cache.build_from(
+        "server",
+        hash(Stage::Server),
+        debootstrap_rebuilt || force(Stage::Server),
+        &debootstrap_dir,
+        |partial_dir| server(partial_dir, manifest),
+    )?;
+    if done(Stage::Server) {
+        return Ok(());
+    }
 
-        log::info!("Allocating image file");
-        let image_file = partial_dir.join("image.raw");
-        Command::new("fallocate")
-            .arg("--length")
-            .arg("32GiB")
-            .arg("--posix")
-            .arg(&image_file)
-            .status()
-            .and_then(check_status)?;
+    let (desktop_dir, desktop_rebuilt) = cache.build_from(
+        "desktop",
+        hash(Stage::Desktop),
+        server_rebuilt || force(Stage::Desktop),
+        &server_dir,
+        |partial_dir| desktop(partial_dir, manifest),
+    )?;
+    if done(Stage::Desktop) {
+        return Ok(());
+    }
 
-        log::info!("Partitioning image file");
-        Command::new("sgdisk")
-            .arg("--new=1:0:+512M")
-            .arg("--typecode=1:0xef00")
-            .arg("--new=2:0:0")
-            .arg("--typecode=2:0x8304")
-            .arg(&image_file)
-            .status()
-            .and_then(check_status)?;
+    let image_force = desktop_rebuilt || force(Stage::Image);
+    let (image_dir, _image_rebuilt) =
+        cache.build("image", hash(Stage::Image), image_force, |partial_dir| {
+            fs::create_dir(partial_dir)?;
+
+            //TODO: move logic to Rust as much as possible
 
-        log::info!("Using loopback device");
-        Loopback::new(&image_file)?.with(|loopback| {
-            log::info!("Formatting EFI partition");
-            //TODO: safer way of getting partition 1
-            let part1_file = format!("{}p1", loopback.device().display());
-            Command::new("mkfs.fat")
-                .arg("-F")
-                .arg("32")
-                .arg(&part1_file)
+            log::info!("Allocating image file");
+            let image_file = partial_dir.join("image.raw");
+            Command::new("fallocate")
+                .arg("--length")
+                .arg(image_size.to_string())
+                .arg("--posix")
+                .arg(&image_file)
                 .status()
                 .and_then(check_status)?;
 
-            log::info!("Formatting BTRFS partition");
-            //TODO: safer way of getting partition 2
-            let part2_file = format!("{}p2", loopback.device().display());
-            Command::new("mkfs.btrfs")
-                .arg(&part2_file)
+            log::info!("Partitioning image file");
+            Command::new("sgdisk")
+                .arg(format!("--new=1:0:+{}K", esp_size / 1024))
+                .arg("--typecode=1:0xef00")
+                .arg("--new=2:0:0")
+                .arg("--typecode=2:0x8304")
+                .arg(&image_file)
                 .status()
                 .and_then(check_status)?;
 
-            log::info!("Mounting BTRFS partition");
-            //TODO: use temporary directory?
-            let mount_dir = partial_dir.join("mount");
-            fs::create_dir(&mount_dir)?;
-            Mount::new(&part2_file, &mount_dir, "btrfs", 0, None)?.with(|_mount| {
-                for subvolume in &["@root", "@root/home", "@root/tmp", "@root/var"] {
-                    log::info!("Creating subvolume {}", subvolume);
-                    Command::new("btrfs")
-                        .arg("subvolume")
-                        .arg("create")
-                        .arg(&mount_dir.join(subvolume))
-                        .status()
-                        .and_then(check_status)?;
-                }
-
-                log::info!("Setting subvolume @root as default");
-                let root_dir = mount_dir.join("@root");
-                Command::new("btrfs")
-                    .arg("subvolume")
-                    .arg("set-default")
-                    .arg(&root_dir)
+            log::info!("Using loopback device");
+            Loopback::new(&image_file)?.with(|loopback| {
+                log::info!("Formatting EFI partition");
+                //TODO: safer way of getting partition 1
+                let part1_file = format!("{}p1", loopback.device().display());
+                Command::new("mkfs.fat")
+                    .arg("-F")
+                    .arg("32")
+                    .arg(&part1_file)
                     .status()
                     .and_then(check_status)?;
 
-                log::info!("Copying desktop files");
-                Command::new("cp")
-                    .arg("--archive")
-                    .arg("--no-target-directory")
-                    .arg(&desktop_dir)
-                    .arg(&root_dir)
+                log::info!("Formatting BTRFS partition");
+                //TODO: safer way of getting partition 2
+                let part2_file = format!("{}p2", loopback.device().display());
+                Command::new("mkfs.btrfs")
+                    .arg(&part2_file)
                     .status()
                     .and_then(check_status)?;
 
-                let efi_dir = root_dir.join("boot/efi");
-                if !efi_dir.exists() {
-                    log::info!("Creating EFI directory");
-                    fs::create_dir(&efi_dir)?;
-                }
-
-                log::info!("Mounting EFI directory");
-                Mount::new(&part1_file, &efi_dir, "vfat", 0, None)?.with(|_efi_mount| {
-                    log::info!("Getting root UUID");
-                    let root_uuid = {
-                        let output = Command::new("findmnt")
-                            .arg("--noheadings")
-                            .arg("--output")
-                            .arg("UUID")
-                            .arg("--mountpoint")
-                            .arg(&mount_dir)
-                            .stdout(Stdio::piped())
-                            .spawn()?
-                            .wait_with_output()
-                            .and_then(check_output)?;
-
-                        str::from_utf8(&output.stdout)
-                            .map(|x| x.trim().to_string())
-                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
-                    };
-
-                    let efi_partuuid = {
-                        let output = Command::new("findmnt")
-                            .arg("--noheadings")
-                            .arg("--output")
-                            .arg("PARTUUID")
-                            .arg("--mountpoint")
-                            .arg(&efi_dir)
-                            .stdout(Stdio::piped())
-                            .spawn()?
-                            .wait_with_output()
-                            .and_then(check_output)?;
-
-                        str::from_utf8(&output.stdout)
-                            .map(|x| x.trim().to_string())
-                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
-                    };
-
-                    image(&root_dir, &root_uuid, &efi_partuuid)
-                })?;
-
-                for (old, new) in &[
-                    ("@root/home", "@home"),
-                    ("@root/tmp", "@tmp"),
-                    ("@root/var", "@var"),
-                ] {
-                    log::info!("Moving subvolume {} to {}", old, new);
-                    fs::rename(&mount_dir.join(old), &mount_dir.join(new))?;
-                    fs::create_dir(&mount_dir.join(old))?;
-                }
-
-                log::info!("Snapshot @root as @root.old");
-                Command::new("btrfs")
-                    .arg("subvolume")
-                    .arg("snapshot")
-                    .arg("-r")
-                    .arg(&root_dir)
-                    .arg(&mount_dir.join("@root.old"))
-                    .status()
-                    .and_then(check_status)?;
+                log::info!("Mounting BTRFS partition");
+                //TODO: use temporary directory?
+                let mount_dir = partial_dir.join("mount");
+                fs::create_dir(&mount_dir)?;
+                Mount::new(&part2_file, &mount_dir, "btrfs", 0, None)?.with(|_mount| {
+                    let root_name = generation_name(1);
+                    let root_dir = mount_dir.join(&root_name);
+                    log::info!("Creating subvolume {}", root_dir.display());
+                    btrfs::create_subvolume(&root_dir)?;
+                    for name in &manifest.image.subvolumes {
+                        let subvolume_dir = root_dir.join(name);
+                        log::info!("Creating subvolume {}", subvolume_dir.display());
+                        btrfs::create_subvolume(subvolume_dir)?;
+                    }
+
+                    log::info!("Setting subvolume {} as default", root_name);
+                    btrfs::set_default(&root_dir)?;
+
+                    log::info!("Copying desktop files");
+                    Command::new("cp")
+                        .arg("--archive")
+                        .arg("--no-target-directory")
+                        .arg(&desktop_dir)
+                        .arg(&root_dir)
+                        .status()
+                        .and_then(check_status)?;
 
-                log::info!("Snapshot @root as @root.original");
-                Command::new("btrfs")
-                    .arg("subvolume")
-                    .arg("snapshot")
-                    .arg("-r")
-                    .arg(&root_dir)
-                    .arg(&mount_dir.join("@root.original"))
-                    .status()
-                    .and_then(check_status)?;
+                    let efi_dir = root_dir.join("boot/efi");
+                    if !efi_dir.exists() {
+                        log::info!("Creating EFI directory");
+                        fs::create_dir(&efi_dir)?;
+                    }
+
+                    log::info!("Mounting EFI directory");
+                    Mount::new(&part1_file, &efi_dir, "vfat", 0, None)?.with(|_efi_mount| {
+                        log::info!("Getting root UUID");
+                        let root_uuid = filesystem_uuid(&mount_dir)?;
+
+                        log::info!("Getting EFI PARTUUID");
+                        let efi_partuuid = partition_uuid(&efi_dir)?;
+
+                        image(
+                            &root_dir,
+                            &root_uuid,
+                            &efi_partuuid,
+                            &manifest.image.subvolumes,
+                        )
+                    })?;
+
+                    for old in &manifest.image.subvolumes {
+                        let new = format!("@{}", old);
+                        log::info!("Moving subvolume {}/{} to {}", root_name, old, new);
+                        fs::rename(root_dir.join(old), mount_dir.join(new))?;
+                        fs::create_dir(root_dir.join(old))?;
+                    }
+
+                    log::info!("Snapshot {} as @root.original", root_name);
+                    btrfs::snapshot(&root_dir, mount_dir.join("@root.original"), true)?;
+
+                    // Used by factory resets of @var
+                    if manifest.image.subvolumes.iter().any(|name| name == "var") {
+                        log::info!("Snapshot @var as @var.original");
+                        btrfs::snapshot(
+                            mount_dir.join("@var"),
+                            mount_dir.join("@var.original"),
+                            true,
+                        )?;
+                    }
+
+                    Ok(())
+                })?;
 
                 Ok(())
             })?;
@@ -348,8 +492,58 @@ pub fn build() -> io::Result<()> {
             Ok(())
         })?;
 
-        Ok(())
-    })?;
+    if let Some(output) = &options.output {
+        log::info!("Copying image to {}", output.display());
+        Command::new("cp")
+            .arg("--reflink=auto")
+            .arg("--sparse=always")
+            .arg(image_dir.join("image.raw"))
+            .arg(output)
+            .status()
+            .and_then(check_status)?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forced_stages_are_stale() {
+        let dir = crate::util::create_temp_dir("pop-core-build").unwrap();
+        let manifest = Manifest::default();
+        for (stage, hash) in Stage::ALL.iter().zip(stage_hashes(&manifest).unwrap()) {
+            fs::create_dir(dir.join(stage.name())).unwrap();
+            fs::write(dir.join(format!("{}.hash", stage.name())), hash).unwrap();
+        }
+
+        let mut options = BuildOptions {
+            cache_dir: dir.clone(),
+            ..BuildOptions::default()
+        };
+        let states = |options: &BuildOptions| -> Vec<StageState> {
+            cache_status(&manifest, options)
+                .unwrap()
+                .stages
+                .into_iter()
+                .map(|(_, state)| state)
+                .collect()
+        };
+        assert_eq!(states(&options), [StageState::Cached; 4]);
+
+        options.force_from = Some(Stage::Desktop);
+        assert_eq!(
+            states(&options),
+            [
+                StageState::Cached,
+                StageState::Cached,
+                StageState::Stale,
+                StageState::Stale
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,147 @@
+use sha2::{Digest, Sha256};
 use std::{
     collections::BTreeMap,
-    fs, io,
+    fs::{self, File},
+    io,
     path::{Path, PathBuf},
+    process::Command,
     thread,
 };
 
+use crate::{btrfs, umount, util::check_status, MountInfo};
+
+/// Hashes the inputs of a cached build, to detect when it needs to be rebuilt.
+#[derive(Debug, Default)]
+pub struct InputHasher {
+    sha256: Sha256,
+}
+
+impl InputHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn part_len(&mut self, len: u64) {
+        self.sha256.update(len.to_le_bytes());
+    }
+
+    /// Adds an input. Inputs are length prefixed, so their boundaries are part of the hash.
+    pub fn input(&mut self, name: &str, data: &[u8]) -> &mut Self {
+        for part in [name.as_bytes(), data] {
+            self.part_len(part.len() as u64);
+            self.sha256.update(part);
+        }
+        self
+    }
+
+    /// Adds the contents of the file at `path` as an input, like [`InputHasher::input`] but
+    /// without reading it into memory. Returns false if the file does not exist.
+    pub fn input_file(&mut self, name: &str, path: &Path) -> io::Result<bool> {
+        let mut file = match File::open(path) {
+            Ok(ok) => ok,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        self.part_len(name.len() as u64);
+        self.sha256.update(name.as_bytes());
+        self.part_len(file.metadata()?.len());
+        io::copy(&mut file, &mut self.sha256)?;
+        Ok(true)
+    }
+
+    /// Returns the SHA-256 of the inputs as hex.
+    pub fn finish(self) -> String {
+        self.sha256
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// Returns the mounts in `mounts` at or under `path`, deepest and most recently mounted first.
+/// `mounts` must be in the order they were mounted, as in mountinfo.
+fn mounts_under<'a>(mounts: &'a [MountInfo], path: &Path) -> Vec<&'a MountInfo> {
+    let mut under: Vec<&MountInfo> = mounts
+        .iter()
+        .rev()
+        .filter(|mount| mount.mount_point.starts_with(path))
+        .collect();
+    // Stable, so mounts stacked on the same path stay in reverse order
+    under.sort_by_key(|mount| std::cmp::Reverse(mount.mount_point.components().count()));
+    under
+}
+
+/// Unmounts everything mounted at or under `path`, such as the ESP or bind mounts left behind
+/// by a build that crashed, so that removing it does not recurse into other filesystems. Fails
+/// if anything stays mounted.
+fn unmount_under(path: &Path) -> io::Result<()> {
+    for mount in mounts_under(&MountInfo::all()?, path) {
+        let mount_point = &mount.mount_point;
+        log::warn!(
+            "Unmounting {}, which was left mounted in the cache",
+            mount_point.display()
+        );
+        umount(mount_point, false).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "refusing to remove {}, as {} is mounted and failed to unmount: {}",
+                    path.display(),
+                    mount_point.display(),
+                    err
+                ),
+            )
+        })?;
+    }
+
+    if let Some(mount) = mounts_under(&MountInfo::all()?, path).first() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "refusing to remove {}, as {} is still mounted",
+                path.display(),
+                mount.mount_point.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Removes the file, directory or subvolume at `path`, after unmounting anything in it.
+fn remove_entry(path: &Path) -> io::Result<()> {
+    unmount_under(path)?;
+
+    //TODO: rename before removing
+    if btrfs::is_subvolume(path)? {
+        btrfs::delete_subvolume(path)?;
+    } else if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Returns the path of the file recording the input hash of the entry at `path`.
+fn hash_path(path: &Path) -> PathBuf {
+    let mut hash_path = path.as_os_str().to_owned();
+    hash_path.push(".hash");
+    PathBuf::from(hash_path)
+}
+
 pub struct Cache {
     path: PathBuf,
     cleaned: bool,
+    /// Entries are subvolumes, so they can be snapshotted.
+    btrfs: bool,
 }
 
 impl Cache {
     pub fn new<P: AsRef<Path>, F: Fn(&str) -> bool>(path: P, retain: F) -> io::Result<Self> {
         let path = path.as_ref();
         if !path.is_dir() {
-            fs::create_dir_all(&path)?;
+            fs::create_dir_all(path)?;
         }
         let path = fs::canonicalize(path)?;
         let mut cleaned = false;
@@ -28,17 +155,17 @@ impl Cache {
             })?;
             if !retain(&file_name) {
                 let entry_path = entry.path();
-                eprintln!("Cache::new: removing {}", entry_path.display());
-                //TODO: rename before removing
-                if entry_path.is_dir() {
-                    fs::remove_dir_all(&entry_path)?;
-                } else {
-                    fs::remove_file(&entry_path)?;
-                }
+                log::info!("Removing {} from cache", entry_path.display());
+                remove_entry(&entry_path)?;
                 cleaned = true;
             }
         }
-        Ok(Self { path, cleaned })
+        let btrfs = btrfs::is_btrfs(&path)?;
+        Ok(Self {
+            path,
+            cleaned,
+            btrfs,
+        })
     }
 
     pub fn path(&self) -> &Path {
@@ -53,7 +180,18 @@ impl Cache {
         Self::new(self.path().join(name), retain)
     }
 
-    fn build_inner(&mut self, name: &str, force: bool) -> io::Result<(PathBuf, Option<PathBuf>)> {
+    /// Returns true if the entry `name` exists and was built from inputs with `hash`.
+    pub fn is_current(&self, name: &str, hash: &str) -> bool {
+        let path = self.path().join(name);
+        path.exists() && fs::read_to_string(hash_path(&path)).map_or(false, |data| data == hash)
+    }
+
+    fn build_inner(
+        &mut self,
+        name: &str,
+        hash: &str,
+        force: bool,
+    ) -> io::Result<(PathBuf, Option<PathBuf>)> {
         let partial_prefix = "partial.";
         if name.starts_with(partial_prefix) {
             return Err(io::Error::new(
@@ -64,14 +202,18 @@ impl Cache {
 
         let path = self.path().join(name);
         if path.exists() {
-            if force {
-                eprintln!("Cache::build: forcing rebuild of {}", path.display());
-                //TODO: rename before removing
-                if path.is_dir() {
-                    fs::remove_dir_all(&path)?;
+            let current = self.is_current(name, hash);
+            if force || !current {
+                if force {
+                    log::info!("Forcing rebuild of {}", path.display());
                 } else {
-                    fs::remove_file(&path)?;
+                    log::info!("Inputs of {} changed, rebuilding it", path.display());
+                }
+                let hash_path = hash_path(&path);
+                if hash_path.exists() {
+                    fs::remove_file(hash_path)?;
                 }
+                remove_entry(&path)?;
             } else {
                 return Ok((path, None));
             }
@@ -89,18 +231,22 @@ impl Cache {
         Ok((path, Some(partial_path)))
     }
 
+    /// Returns the entry `name`, building it with `f` unless it exists and its inputs still have
+    /// `hash`. Also returns whether it was built.
     pub fn build<F: Fn(&Path) -> io::Result<()>>(
         &mut self,
         name: &str,
+        hash: &str,
         force: bool,
         f: F,
     ) -> io::Result<(PathBuf, bool)> {
-        let (path, partial_path_opt) = self.build_inner(name, force)?;
+        let (path, partial_path_opt) = self.build_inner(name, hash, force)?;
         match partial_path_opt {
             Some(partial_path) => {
                 f(&partial_path)?;
 
                 fs::rename(partial_path, &path)?;
+                fs::write(hash_path(&path), hash)?;
 
                 Ok((path, true))
             }
@@ -108,9 +254,60 @@ impl Cache {
         }
     }
 
+    /// Like [`build`](Self::build), with the partial entry created as an empty directory before
+    /// calling `f`. On btrfs it is a subvolume, so that entries built from it can snapshot it.
+    pub fn build_dir<F: Fn(&Path) -> io::Result<()>>(
+        &mut self,
+        name: &str,
+        hash: &str,
+        force: bool,
+        f: F,
+    ) -> io::Result<(PathBuf, bool)> {
+        let btrfs = self.btrfs;
+        self.build(name, hash, force, |partial_path| {
+            if btrfs {
+                btrfs::create_subvolume(partial_path)?;
+            } else {
+                fs::create_dir(partial_path)?;
+            }
+            f(partial_path)
+        })
+    }
+
+    /// Like [`build`](Self::build), with the partial entry created as a copy of the directory
+    /// `parent` before calling `f`. If `parent` is a subvolume, the copy is a writable snapshot,
+    /// and otherwise a copy sharing data with it where the filesystem supports reflinks.
+    pub fn build_from<F: Fn(&Path) -> io::Result<()>>(
+        &mut self,
+        name: &str,
+        hash: &str,
+        force: bool,
+        parent: &Path,
+        f: F,
+    ) -> io::Result<(PathBuf, bool)> {
+        let btrfs = self.btrfs;
+        self.build(name, hash, force, |partial_path| {
+            if btrfs && btrfs::is_subvolume(parent)? {
+                log::info!("Snapshotting {}", parent.display());
+                btrfs::snapshot(parent, partial_path, false)?;
+            } else {
+                log::info!("Copying {}", parent.display());
+                Command::new("cp")
+                    .arg("--archive")
+                    .arg("--reflink=auto")
+                    .arg("--no-target-directory")
+                    .arg(parent)
+                    .arg(partial_path)
+                    .status()
+                    .and_then(check_status)?;
+            }
+            f(partial_path)
+        })
+    }
+
     pub fn build_parallel<F: Fn(&Path) -> io::Result<()> + Send>(
         &mut self,
-        names: BTreeMap<String, F>,
+        names: BTreeMap<String, (String, F)>,
         force: bool,
     ) -> BTreeMap<String, io::Result<(PathBuf, bool)>> {
         let mut results = BTreeMap::new();
@@ -118,8 +315,8 @@ impl Cache {
         thread::scope(|s| {
             let mut threads = BTreeMap::new();
 
-            for (name, f) in names {
-                match self.build_inner(&name, force) {
+            for (name, (hash, f)) in names {
+                match self.build_inner(&name, &hash, force) {
                     Ok((path, partial_path_opt)) => match partial_path_opt {
                         Some(partial_path) => {
                             threads.insert(
@@ -127,6 +324,7 @@ impl Cache {
                                 s.spawn(move || {
                                     f(&partial_path)?;
                                     fs::rename(partial_path, &path)?;
+                                    fs::write(hash_path(&path), hash)?;
                                     Ok(path)
                                 }),
                             );
@@ -156,3 +354,100 @@ impl Cache {
         results
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_hash() {
+        let hash = |inputs: &[(&str, &str)]| {
+            let mut hasher = InputHasher::new();
+            for (name, data) in inputs {
+                hasher.input(name, data.as_bytes());
+            }
+            hasher.finish()
+        };
+        let packages = hash(&[("packages", "bash vim")]);
+        assert_eq!(packages.len(), 64);
+        assert_eq!(packages, hash(&[("packages", "bash vim")]));
+        assert_ne!(packages, hash(&[("packages", "bash")]));
+        assert_ne!(hash(&[("a", "bc")]), hash(&[("ab", "c")]));
+
+        // Plain SHA-256 as hex, like the hash files of existing caches
+        assert_eq!(
+            InputHasher::new().finish(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let dir = crate::util::create_temp_dir("pop-core-cache").unwrap();
+        let file = dir.join("pop-core");
+        fs::write(&file, "binary").unwrap();
+        let mut hasher = InputHasher::new();
+        assert!(hasher.input_file("pop-core", &file).unwrap());
+        assert_eq!(hasher.finish(), hash(&[("pop-core", "binary")]));
+        let mut hasher = InputHasher::new();
+        assert!(!hasher.input_file("missing", &dir.join("missing")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mounts_under_path() {
+        let mount = |mount_point: &str, source: &str| MountInfo {
+            major: 0,
+            minor: 21,
+            root: PathBuf::from("/"),
+            mount_point: PathBuf::from(mount_point),
+            fstype: "tmpfs".to_string(),
+            source: source.to_string(),
+        };
+        // In mount order, with a second mount stacked on /cache/image/mount
+        let mounts = [
+            mount("/", "root"),
+            mount("/cache/image/mount", "lower"),
+            mount("/cache/image2", "sibling"),
+            mount("/cache/image/mount/boot/efi", "esp"),
+            mount("/cache/image/mount", "upper"),
+            mount("/cache/image-old/mount", "sibling"),
+            mount("/cache/image", "image"),
+        ];
+        let sources: Vec<_> = mounts_under(&mounts, Path::new("/cache/image"))
+            .into_iter()
+            .map(|mount| mount.source.as_str())
+            .collect();
+        assert_eq!(sources, ["esp", "upper", "lower", "image"]);
+        assert!(mounts_under(&mounts, Path::new("/cache/image/mount/boot/efi/EFI")).is_empty());
+    }
+
+    #[test]
+    fn build_from_parent() {
+        let dir = crate::util::create_temp_dir("pop-core-cache").unwrap();
+        let mut cache = Cache::new(&dir, |_| true).unwrap();
+
+        let (parent, built) = cache
+            .build_dir("parent", "1", false, |path| {
+                fs::write(path.join("file"), "parent")
+            })
+            .unwrap();
+        assert!(built);
+        let (child, built) = cache
+            .build_from("child", "1", false, &parent, |path| {
+                fs::write(path.join("child"), "child")
+            })
+            .unwrap();
+        assert!(built);
+        assert_eq!(fs::read_to_string(child.join("file")).unwrap(), "parent");
+
+        // Reused with the same hash, and rebuilt with another
+        let (_, built) = cache
+            .build_dir("parent", "1", false, |_| panic!("rebuilt"))
+            .unwrap();
+        assert!(!built);
+        assert!(cache.is_current("child", "1"));
+        let (_, built) = cache.build_dir("parent", "2", false, |_| Ok(())).unwrap();
+        assert!(built);
+        assert!(!parent.join("file").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Read},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    process::Command,
+};
+
+use crate::{btrfs, with_top_dir, GenerationMetadata, Generations};
+
+/// Location of the compressed output of the command that created a root, inside that root.
+pub const LOG_FILE: &str = "usr/lib/pop-core/generation.log.gz";
+
+/// Quotes `arg` for a POSIX shell.
+fn shell_quote(arg: &OsStr) -> String {
+    let arg = String::from_utf8_lossy(arg.as_bytes());
+    if !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_./=:@%+,".contains(&b))
+    {
+        return arg.into_owned();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Wraps `command` in `script`, which runs it on a pseudo terminal and copies its output to
+/// `log_path` as well as to this terminal. The command stays interactive, and the exit status
+/// of `script` is that of the command.
+///
+/// `script` runs the command with `$SHELL -c`, so it is set to `/bin/sh` for the quoting to
+/// work, as the shell of the invoking user may not be a POSIX shell.
+pub(crate) fn logged_command(command: &Command, log_path: &Path) -> Command {
+    let mut shell_command = shell_quote(command.get_program());
+    for arg in command.get_args() {
+        shell_command.push(' ');
+        shell_command.push_str(&shell_quote(arg));
+    }
+
+    let mut script = Command::new("script");
+    script
+        .env("SHELL", "/bin/sh")
+        .arg("--quiet")
+        .arg("--return")
+        .arg("--log-out")
+        .arg(log_path)
+        .arg("--command")
+        .arg(shell_command);
+    script
+}
+
+/// Compresses the log at `log_path` into [`LOG_FILE`] in `root_dir`, removing `log_path`.
+pub(crate) fn store_log(log_path: &Path, root_dir: &Path) -> io::Result<()> {
+    let dest = root_dir.join(LOG_FILE);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut encoder = GzEncoder::new(File::create(&dest)?, Compression::default());
+    io::copy(&mut File::open(log_path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(log_path)
+}
+
+/// Reads the log of the command that created `root_dir`.
+pub fn read_log<P: AsRef<Path>>(root_dir: P) -> io::Result<Vec<u8>> {
+    let root_dir = root_dir.as_ref();
+    // The log is only for this root if the metadata is, as snapshots inherit both
+    let has_log = GenerationMetadata::read(root_dir)?
+        .and_then(|metadata| metadata.run)
+        .map_or(false, |run| run.logged);
+    if !has_log {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no command log", root_dir.display()),
+        ));
+    }
+
+    let mut data = Vec::new();
+    GzDecoder::new(File::open(root_dir.join(LOG_FILE))?).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Returns the output of the command that created a root. Without a target, the default root
+/// is used.
+pub fn generation_log(target: Option<String>) -> io::Result<Vec<u8>> {
+    with_top_dir(|top_dir| {
+        let generations = Generations::load(top_dir, btrfs::subvolume_id("/")?)?;
+        let name = match target {
+            Some(some) => some,
+            None => generations
+                .default()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Default subvolume is not a root generation",
+                    )
+                })?
+                .name(),
+        };
+        let (_, root_dir) = generations.find(&name)?;
+        read_log(root_dir)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote() {
+        assert_eq!(
+            shell_quote(OsStr::new("--machine=pop-os")),
+            "--machine=pop-os"
+        );
+        assert_eq!(shell_quote(OsStr::new("")), "''");
+        assert_eq!(shell_quote(OsStr::new("a b")), "'a b'");
+        assert_eq!(shell_quote(OsStr::new("it's")), "'it'\\''s'");
+    }
+
+    #[test]
+    fn logged() {
+        let mut command = Command::new("systemd-nspawn");
+        command.arg("--").arg("bash").arg("-c").arg("echo $HOME");
+        let script = logged_command(&command, Path::new("/tmp/log"));
+        let args: Vec<_> = script.get_args().collect();
+        assert_eq!(
+            args.last().unwrap(),
+            &OsStr::new("systemd-nspawn -- bash -c 'echo $HOME'")
+        );
+        assert!(script
+            .get_envs()
+            .any(|(key, value)| key == "SHELL" && value == Some(OsStr::new("/bin/sh"))));
+    }
+}
@@ -0,0 +1,95 @@
+use serde::Deserialize;
+use std::{fs, io, path::Path};
+
+/// Where the pop-core configuration is read from.
+pub const CONFIG_FILE: &str = "/etc/pop-core/config.toml";
+
+/// Which old roots are kept when they are garbage collected.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RetentionPolicy {
+    /// Keep this many of the newest generations.
+    pub keep_last: usize,
+    /// Keep generations created less than this many days ago. Zero disables this.
+    pub keep_days: u64,
+    /// Collect garbage after every committed update. Off by default, so existing installs keep
+    /// their roots until this or `pop-core gc` is used.
+    pub auto: bool,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            keep_last: 3,
+            keep_days: 7,
+            auto: false,
+        }
+    }
+}
+
+/// How much free space updates need.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SpacePolicy {
+    /// Refuse to start an update with less than this many MiB free. Zero disables the check.
+    pub min_free_mib: u64,
+}
+
+impl Default for SpacePolicy {
+    fn default() -> Self {
+        Self { min_free_mib: 1024 }
+    }
+}
+
+/// The contents of [`CONFIG_FILE`]. Every setting has a default, so the file is optional.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub gc: RetentionPolicy,
+    pub space: SpacePolicy,
+}
+
+impl Config {
+    /// Parses a configuration file.
+    pub fn parse(data: &str) -> io::Result<Self> {
+        toml::from_str(data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Reads the configuration at `path`, using defaults if it does not exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(data) => Self::parse(&data).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to parse {}: {}", path.display(), err),
+                )
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.gc.keep_last, 3);
+        assert!(!config.gc.auto);
+
+        let config = Config::parse("[gc]\nkeep-last = 5\nauto = true\n").unwrap();
+        assert_eq!(config.gc.keep_last, 5);
+        assert_eq!(config.gc.keep_days, 7);
+        assert!(config.gc.auto);
+        assert_eq!(config.space.min_free_mib, 1024);
+
+        let config = Config::parse("[space]\nmin-free-mib = 0\n").unwrap();
+        assert_eq!(config.space.min_free_mib, 0);
+
+        assert!(Config::parse("[gc]\nkeep = 5\n").is_err());
+    }
+}
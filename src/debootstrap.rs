@@ -28,7 +28,20 @@ impl Debootstrap {
         }
     }
 
-    //TODO: builder functions to set each argument
+    pub fn suite(mut self, suite: impl Into<String>) -> Self {
+        self.suite = suite.into();
+        self
+    }
+
+    pub fn arch(mut self, arch: impl Into<String>) -> Self {
+        self.arch = arch.into();
+        self
+    }
+
+    pub fn mirror(mut self, mirror: impl Into<String>) -> Self {
+        self.mirror = mirror.into();
+        self
+    }
 
     pub fn include_package(mut self, package: impl Into<String>) -> Self {
         //TODO: ensure there are no commas in package?
@@ -50,16 +63,16 @@ impl Debootstrap {
     pub fn command(&self) -> Command {
         let mut command = Command::new("debootstrap");
         if !self.include.is_empty() {
-            command.arg(&format!("--include={}", self.include.join(",")));
+            command.arg(format!("--include={}", self.include.join(",")));
         }
         if !self.exclude.is_empty() {
-            command.arg(&format!("--exclude={}", self.exclude.join(",")));
+            command.arg(format!("--exclude={}", self.exclude.join(",")));
         }
         if let Some(variant) = &self.variant {
-            command.arg(&format!("--variant={}", variant));
+            command.arg(format!("--variant={}", variant));
         }
         command
-            .arg(&format!("--arch={}", self.arch))
+            .arg(format!("--arch={}", self.arch))
             .arg(&self.suite)
             .arg(&self.target)
             .arg(&self.mirror);
@@ -0,0 +1,432 @@
+use serde::Serialize;
+use std::{cmp::Ordering, collections::BTreeMap, fmt, fs, io, path::Path};
+
+use crate::{
+    btrfs::{self, SubvolumeInfo},
+    dpkg::{compare_versions, file_owners, installed_packages},
+    with_top_dir, Generations,
+};
+
+/// How a package differs between two roots.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageChangeKind {
+    Added,
+    Removed,
+    Upgraded,
+    Downgraded,
+}
+
+impl PackageChangeKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Removed => "removed",
+            Self::Upgraded => "upgraded",
+            Self::Downgraded => "downgraded",
+        }
+    }
+}
+
+/// A package that differs between two roots.
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct PackageChange {
+    pub name: String,
+    pub change: PackageChangeKind,
+    /// Version in the `from` root, if installed there.
+    pub from_version: Option<String>,
+    /// Version in the `to` root, if installed there.
+    pub to_version: Option<String>,
+}
+
+/// A file whose data changed, owned by the package `package`.
+#[derive(Debug, Eq, PartialEq, Serialize)]
+pub struct OwnedFile {
+    pub path: String,
+    pub package: String,
+}
+
+/// Files whose data changed between two roots, grouped by whether a package owns them.
+#[derive(Debug, Default, Serialize)]
+pub struct FileChanges {
+    pub owned: Vec<OwnedFile>,
+    pub unowned: Vec<String>,
+}
+
+/// The differences between two roots, as reported by `pop-core diff`.
+#[derive(Debug, Serialize)]
+pub struct Diff {
+    pub from: String,
+    pub to: String,
+    pub packages: Vec<PackageChange>,
+    /// Only compared if requested with [`DiffOptions::files`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<FileChanges>,
+}
+
+/// Options for [`diff`].
+#[derive(Debug, Default)]
+pub struct DiffOptions {
+    /// Also find files whose data changed, which requires `to` to descend from `from`.
+    pub files: bool,
+}
+
+impl Diff {
+    /// Compares the package versions of two roots, sorted by package name.
+    pub fn packages(
+        from: &BTreeMap<String, String>,
+        to: &BTreeMap<String, String>,
+    ) -> Vec<PackageChange> {
+        let mut changes = Vec::new();
+        for (name, from_version) in from {
+            let change = match to.get(name) {
+                Some(to_version) => match compare_versions(from_version, to_version) {
+                    Ordering::Less => PackageChangeKind::Upgraded,
+                    Ordering::Greater => PackageChangeKind::Downgraded,
+                    Ordering::Equal => continue,
+                },
+                None => PackageChangeKind::Removed,
+            };
+            changes.push(PackageChange {
+                name: name.clone(),
+                change,
+                from_version: Some(from_version.clone()),
+                to_version: to.get(name).cloned(),
+            });
+        }
+        for (name, to_version) in to {
+            if !from.contains_key(name) {
+                changes.push(PackageChange {
+                    name: name.clone(),
+                    change: PackageChangeKind::Added,
+                    from_version: None,
+                    to_version: Some(to_version.clone()),
+                });
+            }
+        }
+        changes.sort_by(|a, b| a.name.cmp(&b.name));
+        changes
+    }
+
+    /// Groups changed `paths` by whether they are in `owners`.
+    pub fn files(paths: Vec<String>, owners: &BTreeMap<String, String>) -> FileChanges {
+        let mut files = FileChanges::default();
+        for path in paths {
+            match owners.get(&path) {
+                Some(package) => files.owned.push(OwnedFile {
+                    path,
+                    package: package.clone(),
+                }),
+                None => files.unowned.push(path),
+            }
+        }
+        files
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Comparing {} to {}", self.from, self.to)?;
+        for kind in [
+            PackageChangeKind::Added,
+            PackageChangeKind::Removed,
+            PackageChangeKind::Upgraded,
+            PackageChangeKind::Downgraded,
+        ] {
+            for package in self
+                .packages
+                .iter()
+                .filter(|package| package.change == kind)
+            {
+                writeln!(
+                    f,
+                    "{:<10} {:<40} {} -> {}",
+                    kind.name(),
+                    package.name,
+                    package.from_version.as_deref().unwrap_or("-"),
+                    package.to_version.as_deref().unwrap_or("-"),
+                )?;
+            }
+        }
+        let count = |kind| {
+            self.packages
+                .iter()
+                .filter(|package| package.change == kind)
+                .count()
+        };
+        writeln!(
+            f,
+            "{} added, {} removed, {} upgraded, {} downgraded",
+            count(PackageChangeKind::Added),
+            count(PackageChangeKind::Removed),
+            count(PackageChangeKind::Upgraded),
+            count(PackageChangeKind::Downgraded),
+        )?;
+
+        if let Some(files) = &self.files {
+            writeln!(f, "Changed files owned by a package:")?;
+            for file in &files.owned {
+                writeln!(f, "  {:<60} {}", file.path, file.package)?;
+            }
+            writeln!(f, "Changed files not owned by any package:")?;
+            for path in &files.unowned {
+                writeln!(f, "  {}", path)?;
+            }
+            writeln!(
+                f,
+                "{} owned, {} not owned",
+                files.owned.len(),
+                files.unowned.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the transaction after which data written to `to` differs from `from`. `to` must be
+/// a snapshot of `from`, or of a descendant of it in `subvolumes`, and `from` must not have
+/// changed since. This is the creation transaction of the first snapshot on the way, which,
+/// unlike the generation of `from`, does not change if `from` is written to later.
+fn find_new_baseline(
+    from: (&str, &SubvolumeInfo),
+    to: (&str, &SubvolumeInfo),
+    subvolumes: &[SubvolumeInfo],
+) -> io::Result<u64> {
+    let (from_name, from) = from;
+    let (to_name, to) = to;
+    if to.uuid == from.uuid {
+        return Ok(to.generation);
+    }
+
+    let unrelated = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "comparing files needs {} to be a snapshot of {} or of a root created from it",
+                to_name, from_name
+            ),
+        )
+    };
+
+    // Each step goes to an older snapshot, so the walk ends within the number of subvolumes
+    let mut child = to;
+    for _ in 0..=subvolumes.len() {
+        if child.parent_uuid == from.uuid {
+            if from.ctransid > child.otransid {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "files cannot be compared, as {} changed after it was snapshotted",
+                        from_name
+                    ),
+                ));
+            }
+            return Ok(child.otransid);
+        }
+        let parent = subvolumes
+            .iter()
+            .find(|subvolume| subvolume.uuid == child.parent_uuid)
+            .ok_or_else(unrelated)?;
+        if parent.otransid >= child.otransid {
+            return Err(unrelated());
+        }
+        child = parent;
+    }
+    Err(unrelated())
+}
+
+/// Finds files in `to_dir` whose data changed since it was snapshotted from `from_dir`. Roots in
+/// `top_dir` are searched for the snapshots in between.
+fn changed_files(
+    top_dir: &Path,
+    (from_name, from_dir): (&str, &Path),
+    (to_name, to_dir): (&str, &Path),
+) -> io::Result<FileChanges> {
+    let from_info = btrfs::subvolume_info(from_dir)?;
+    let to_info = btrfs::subvolume_info(to_dir)?;
+
+    let mut subvolumes = Vec::new();
+    for entry_res in fs::read_dir(top_dir)? {
+        let path = entry_res?.path();
+        let is_root = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name.starts_with("@root"));
+        if is_root && btrfs::is_subvolume(&path)? {
+            subvolumes.push(btrfs::subvolume_info(&path)?);
+        }
+    }
+    let baseline = find_new_baseline((from_name, &from_info), (to_name, &to_info), &subvolumes)?;
+
+    log::debug!(
+        "Finding files in {} changed after transaction {}",
+        to_dir.display(),
+        baseline
+    );
+    let paths = btrfs::find_new(to_dir, baseline)?
+        .into_iter()
+        .map(|path| Path::new("/").join(path).display().to_string())
+        .collect();
+
+    log::debug!("Reading package file lists of {}", to_dir.display());
+    let owners = file_owners(to_dir)?;
+    Ok(Diff::files(paths, &owners))
+}
+
+fn diff_with_top_dir(
+    top_dir: &Path,
+    from: Option<&str>,
+    to: Option<&str>,
+    options: &DiffOptions,
+) -> io::Result<Diff> {
+    // Diff is read-only, so unlike other commands this does not migrate an older layout
+    let generations = Generations::load(top_dir, btrfs::subvolume_id("/")?)?;
+    let default = generations.default().map(|generation| generation.name());
+    let missing = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no {} root given and there is no default to use", what),
+        )
+    };
+
+    let (to_name, to_dir) = match to.or(default.as_deref()) {
+        Some(name) => generations.find(name)?,
+        None => return Err(missing("to")),
+    };
+    let (from_name, from_dir) = match from {
+        Some(name) => generations.find(name)?,
+        None => generations.find("@root.old").map_err(|_| missing("from"))?,
+    };
+
+    log::debug!("Reading packages of {}", from_name);
+    let from_packages = installed_packages(&from_dir)?;
+    log::debug!("Reading packages of {}", to_name);
+    let to_packages = installed_packages(&to_dir)?;
+
+    let files = if options.files {
+        Some(changed_files(
+            top_dir,
+            (&from_name, &from_dir),
+            (&to_name, &to_dir),
+        )?)
+    } else {
+        None
+    };
+
+    Ok(Diff {
+        packages: Diff::packages(&from_packages, &to_packages),
+        files,
+        from: from_name,
+        to: to_name,
+    })
+}
+
+/// Compares the installed packages of two roots. Without `to` the default root is used, and
+/// without `from` the generation before the default root is used.
+pub fn diff(from: Option<String>, to: Option<String>, options: &DiffOptions) -> io::Result<Diff> {
+    with_top_dir(|top_dir| diff_with_top_dir(top_dir, from.as_deref(), to.as_deref(), options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_changes() {
+        let packages = |list: &[(&str, &str)]| -> BTreeMap<String, String> {
+            list.iter()
+                .map(|(name, version)| (name.to_string(), version.to_string()))
+                .collect()
+        };
+        let from = packages(&[
+            ("bash", "5.1-6"),
+            ("old", "1.0"),
+            ("same", "2"),
+            ("vim", "9.0"),
+        ]);
+        let to = packages(&[
+            ("bash", "5.1-7"),
+            ("new", "1.0"),
+            ("same", "2"),
+            ("vim", "8.2"),
+        ]);
+
+        let changes = Diff::packages(&from, &to);
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|change| (change.name.as_str(), change.change))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("bash", PackageChangeKind::Upgraded),
+                ("new", PackageChangeKind::Added),
+                ("old", PackageChangeKind::Removed),
+                ("vim", PackageChangeKind::Downgraded),
+            ]
+        );
+        assert_eq!(changes[1].from_version, None);
+        assert_eq!(changes[2].to_version, None);
+    }
+
+    #[test]
+    fn file_changes() {
+        let mut owners = BTreeMap::new();
+        owners.insert("/usr/bin/bash".to_string(), "bash".to_string());
+        let files = Diff::files(
+            vec!["/etc/hostname".to_string(), "/usr/bin/bash".to_string()],
+            &owners,
+        );
+        assert_eq!(
+            files.owned,
+            vec![OwnedFile {
+                path: "/usr/bin/bash".to_string(),
+                package: "bash".to_string(),
+            }]
+        );
+        assert_eq!(files.unowned, vec!["/etc/hostname".to_string()]);
+    }
+
+    fn subvolume(id: u8, parent: u8, otransid: u64, ctransid: u64) -> SubvolumeInfo {
+        SubvolumeInfo {
+            id: id.into(),
+            generation: ctransid,
+            created: None,
+            readonly: true,
+            uuid: [id; 16],
+            parent_uuid: [parent; 16],
+            ctransid,
+            otransid,
+        }
+    }
+
+    #[test]
+    fn find_new_baselines() {
+        let from = subvolume(1, 0, 10, 12);
+        let child = subvolume(2, 1, 20, 25);
+        let grandchild = subvolume(3, 2, 30, 35);
+        let subvolumes = [from.clone(), child.clone(), grandchild.clone()];
+
+        // The creation of the first snapshot of from, not the generation of either root
+        assert_eq!(
+            find_new_baseline(("from", &from), ("to", &child), &subvolumes).unwrap(),
+            20
+        );
+        assert_eq!(
+            find_new_baseline(("from", &from), ("to", &grandchild), &subvolumes).unwrap(),
+            20
+        );
+
+        // Written to after it was snapshotted
+        let changed = subvolume(1, 0, 10, 22);
+        assert!(find_new_baseline(("from", &changed), ("to", &child), &subvolumes).is_err());
+
+        // Newer, but not created from it
+        let unrelated = subvolume(4, 9, 40, 45);
+        assert!(find_new_baseline(("from", &from), ("to", &unrelated), &subvolumes).is_err());
+        assert!(find_new_baseline(("from", &child), ("to", &from), &subvolumes).is_err());
+
+        // A missing snapshot in between cannot be checked
+        assert!(find_new_baseline(("from", &from), ("to", &grandchild), &[]).is_err());
+    }
+}
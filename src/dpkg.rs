@@ -0,0 +1,236 @@
+use std::{cmp::Ordering, collections::BTreeMap, fs, io, path::Path};
+
+/// Location of the dpkg database inside a root, relocated from `/var/lib/dpkg` by the image
+/// script so that it is part of the root subvolume.
+pub const DPKG_DIR: &str = "usr/var_lib_dpkg";
+
+/// Location of the dpkg database in roots that were not relocated.
+const LEGACY_DPKG_DIR: &str = "var/lib/dpkg";
+
+/// Parses a dpkg status file into the versions of installed packages. Packages that can be
+/// installed for several architectures at once are named like `libc6:amd64`.
+pub fn parse_status(data: &str) -> BTreeMap<String, String> {
+    let mut packages = BTreeMap::new();
+    for paragraph in data.split("\n\n") {
+        let mut package = None;
+        let mut arch = None;
+        let mut multi_arch_same = false;
+        let mut installed = false;
+        let mut version = None;
+        for line in paragraph.lines() {
+            // Continuation lines start with whitespace
+            if line.starts_with(|c: char| c.is_whitespace()) {
+                continue;
+            }
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key, value.trim()),
+                None => continue,
+            };
+            match key {
+                "Package" => package = Some(value),
+                "Architecture" => arch = Some(value),
+                "Multi-Arch" => multi_arch_same = value == "same",
+                "Status" => installed = value.split_whitespace().nth(2) == Some("installed"),
+                "Version" => version = Some(value),
+                _ => (),
+            }
+        }
+
+        if let (Some(package), Some(version), true) = (package, version, installed) {
+            let name = match arch {
+                Some(arch) if multi_arch_same => format!("{}:{}", package, arch),
+                _ => package.to_string(),
+            };
+            packages.insert(name, version.to_string());
+        }
+    }
+    packages
+}
+
+/// Reads the versions of the packages installed in `root_dir`.
+pub fn installed_packages<P: AsRef<Path>>(root_dir: P) -> io::Result<BTreeMap<String, String>> {
+    let root_dir = root_dir.as_ref();
+    let mut status_file = root_dir.join(DPKG_DIR).join("status");
+    if !status_file.exists() {
+        status_file = root_dir.join(LEGACY_DPKG_DIR).join("status");
+    }
+    let data = fs::read_to_string(&status_file).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to read {}: {}", status_file.display(), err),
+        )
+    })?;
+    Ok(parse_status(&data))
+}
+
+/// Directories merged into `/usr`, which packages may still list files under.
+const MERGED_DIRS: &[&str] = &["/bin/", "/sbin/", "/lib/", "/lib32/", "/lib64/", "/libx32/"];
+
+/// Reads which package owns each path in `root_dir`, from the file lists of installed packages.
+/// Paths are absolute inside the root, and listed under both locations for merged `/usr`
+/// directories.
+pub fn file_owners<P: AsRef<Path>>(root_dir: P) -> io::Result<BTreeMap<String, String>> {
+    let root_dir = root_dir.as_ref();
+    let mut info_dir = root_dir.join(DPKG_DIR).join("info");
+    if !info_dir.exists() {
+        info_dir = root_dir.join(LEGACY_DPKG_DIR).join("info");
+    }
+
+    let mut owners = BTreeMap::new();
+    for entry_res in fs::read_dir(&info_dir)? {
+        let path = entry_res?.path();
+        if path.extension().and_then(|x| x.to_str()) != Some("list") {
+            continue;
+        }
+        let package = match path.file_stem().and_then(|x| x.to_str()) {
+            Some(some) => some.to_string(),
+            None => continue,
+        };
+        for line in fs::read_to_string(&path)?.lines() {
+            if MERGED_DIRS.iter().any(|dir| line.starts_with(dir)) {
+                owners.insert(format!("/usr{}", line), package.clone());
+            }
+            owners.insert(line.to_string(), package.clone());
+        }
+    }
+    Ok(owners)
+}
+
+/// Sort weight of a character in the non-digit parts of a version, where `~` sorts before
+/// everything, even the end of the part, and letters sort before other characters.
+fn order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => i32::from(c),
+        Some(b'~') => -1,
+        Some(c) => i32::from(c) + 256,
+    }
+}
+
+/// Compares upstream versions or revisions the way dpkg does, alternating between non-digit
+/// and digit parts.
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    let non_digit = |s: &[u8], k: usize| s.get(k).map_or(false, |c| !c.is_ascii_digit());
+    let digit = |s: &[u8], k: usize| s.get(k).map_or(false, |c| c.is_ascii_digit());
+    while i < a.len() || j < b.len() {
+        while non_digit(a, i) || non_digit(b, j) {
+            let (ac, bc) = (order(a.get(i).copied()), order(b.get(j).copied()));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+
+        let mut first_diff = Ordering::Equal;
+        while digit(a, i) && digit(b, j) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        if digit(a, i) {
+            return Ordering::Greater;
+        }
+        if digit(b, j) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Splits a version into its epoch, upstream version and revision.
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, version),
+    };
+    match rest.rsplit_once('-') {
+        Some((upstream, revision)) => (epoch, upstream, revision),
+        None => (epoch, rest, ""),
+    }
+}
+
+/// Compares two Debian package versions, like `dpkg --compare-versions`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = split_version(a);
+    let (b_epoch, b_upstream, b_revision) = split_version(b);
+    a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| compare_part(a_upstream, b_upstream))
+        .then_with(|| compare_part(a_revision, b_revision))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions() {
+        for (a, b, ordering) in [
+            ("1.0", "1.0", Ordering::Equal),
+            ("1.0-1", "1.0-0", Ordering::Greater),
+            ("1.0", "1.0-0", Ordering::Equal),
+            ("1.10", "1.9", Ordering::Greater),
+            ("1.0~rc1", "1.0", Ordering::Less),
+            ("1.0~rc1", "1.0~rc2", Ordering::Less),
+            ("1.0a", "1.0", Ordering::Greater),
+            ("1.0a", "1.0+", Ordering::Less),
+            ("1:0.9", "2.0", Ordering::Greater),
+            ("2.35-0ubuntu3.1", "2.35-0ubuntu3", Ordering::Greater),
+            (
+                "6.0.2-76060002.202210150739~1666289067~22.04~fe0ce53",
+                "6.0.2-76060002.202210150739~1666289067~22.04~fe0ce54",
+                Ordering::Less,
+            ),
+            ("0001.2", "1.2", Ordering::Equal),
+        ] {
+            assert_eq!(compare_versions(a, b), ordering, "{} vs {}", a, b);
+            assert_eq!(compare_versions(b, a), ordering.reverse(), "{} vs {}", b, a);
+        }
+    }
+
+    #[test]
+    fn status() {
+        let packages = parse_status(
+            "\
+Package: bash
+Status: install ok installed
+Priority: required
+Architecture: amd64
+Multi-Arch: foreign
+Version: 5.1-6ubuntu1
+Description: GNU Bourne Again SHell
+ Bash is an sh-compatible command language interpreter.
+
+Package: libc6
+Status: install ok installed
+Architecture: amd64
+Multi-Arch: same
+Version: 2.35-0ubuntu3.1
+
+Package: removed
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 1.0
+",
+        );
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages["bash"], "5.1-6ubuntu1");
+        assert_eq!(packages["libc6:amd64"], "2.35-0ubuntu3.1");
+    }
+}
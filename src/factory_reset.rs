@@ -0,0 +1,128 @@
+use std::{io, path::Path};
+
+use crate::{
+    btrfs, load_generations, loader, restore_snapshot,
+    util::{rename_exchange, rename_noreplace},
+    with_locked_top_dir, MountInfo,
+};
+
+/// Options for [`factory_reset`].
+#[derive(Debug, Default)]
+pub struct FactoryResetOptions {
+    /// Also replace `@var` with a snapshot of `@var.original`.
+    pub reset_var: bool,
+    /// Also replace `@home` with an empty subvolume, deleting all user files.
+    pub wipe_home: bool,
+    /// Wait for another running pop-core to finish, instead of failing.
+    pub wait: bool,
+}
+
+/// Returns true if the subvolume `name` in the top level, or anything in it, is mounted.
+fn is_mounted(top_dir: &Path, name: &str) -> io::Result<bool> {
+    let top_mount = MountInfo::from_mount_point(top_dir)?;
+    let subvol = Path::new("/").join(name);
+    Ok(MountInfo::all()?.iter().any(|mount| {
+        mount.major == top_mount.major
+            && mount.minor == top_mount.minor
+            && mount.root.starts_with(&subvol)
+    }))
+}
+
+/// Replaces the subvolume `name` in the top level with one created by `create`, which will be
+/// mounted on the next boot. The old subvolume is deleted if it is not mounted, and otherwise kept
+/// as `{name}.old` until the next reset.
+fn replace_subvolume<F: FnOnce(&Path) -> io::Result<()>>(
+    top_dir: &Path,
+    name: &str,
+    create: F,
+) -> io::Result<()> {
+    let path = top_dir.join(name);
+    let new_name = format!("{}.new", name);
+    let new = top_dir.join(&new_name);
+    let old_name = format!("{}.old", name);
+    let old = top_dir.join(&old_name);
+
+    if new.exists() {
+        log::debug!("Deleting {}", new_name);
+        btrfs::delete_subvolume(&new)?;
+    }
+    if old.exists() {
+        if is_mounted(top_dir, &old_name)? {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} from an earlier reset is still in use, reboot before resetting again",
+                    old_name
+                ),
+            ));
+        }
+        log::info!("Deleting {}", old_name);
+        btrfs::delete_subvolume(&old)?;
+    }
+
+    create(&new)?;
+
+    // The running system keeps using the old subvolume, as mounts do not follow names
+    log::info!("Replacing {} with {}", name, new_name);
+    rename_exchange(&path, &new)?;
+    rename_noreplace(&new, &old)?;
+
+    if is_mounted(top_dir, &old_name)? {
+        log::info!(
+            "Old {} is in use and kept as {} until the next reset",
+            name,
+            old_name
+        );
+    } else {
+        log::info!("Deleting {}", old_name);
+        btrfs::delete_subvolume(&old)?;
+    }
+    Ok(())
+}
+
+fn factory_reset_with_top_dir(top_dir: &Path, options: &FactoryResetOptions) -> io::Result<()> {
+    let generations = load_generations(top_dir)?;
+
+    // Checked before changing anything, so a missing snapshot does not leave a partial reset
+    let var_original = top_dir.join("@var.original");
+    if options.reset_var && !var_original.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "@var.original not found, this image cannot reset @var",
+        ));
+    }
+
+    let root = restore_snapshot(&generations, "@root.original")?;
+    log::info!("Set {} as default subvolume", root.display());
+
+    if options.reset_var {
+        replace_subvolume(top_dir, "@var", |new| {
+            log::info!("Creating writable snapshot of @var.original");
+            btrfs::snapshot(&var_original, new, false)?;
+            Ok(())
+        })?;
+    }
+
+    if options.wipe_home {
+        replace_subvolume(top_dir, "@home", |new| {
+            log::info!("Creating empty @home");
+            btrfs::create_subvolume(new)?;
+            Ok(())
+        })?;
+    }
+
+    loader::set_default_entry("Pop_OS-current")?;
+
+    log::info!("Factory reset complete, reboot to use it");
+    Ok(())
+}
+
+/// Makes a copy of `@root.original` the default root, optionally resetting `@var` and wiping
+/// `@home`, which take effect on the next boot. Older generations are kept, so this can be undone
+/// with a rollback as long as `@home` and `@var` were not reset. If another pop-core is running,
+/// this waits for it if `wait` is true, and fails otherwise.
+pub fn factory_reset(options: &FactoryResetOptions) -> io::Result<()> {
+    with_locked_top_dir(options.wait, |top_dir| {
+        factory_reset_with_top_dir(top_dir, options)
+    })
+}
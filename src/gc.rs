@@ -0,0 +1,208 @@
+use std::{fmt, io, path::Path};
+
+use crate::{
+    btrfs, delete_root, load_generations, loader,
+    util::{format_size, now},
+    with_locked_top_dir, Config, Generations, Pins, RetentionPolicy, CONFIG_FILE,
+};
+
+/// A generation considered for deletion.
+#[derive(Debug)]
+struct Candidate {
+    number: u64,
+    /// Creation time, in seconds since the epoch.
+    created: Option<u64>,
+    /// Booted, default, pinned, or otherwise never deleted.
+    protected: bool,
+}
+
+/// Returns the numbers of the generations `policy` does not keep. `candidates` must be sorted
+/// by number.
+fn select(candidates: &[Candidate], policy: &RetentionPolicy, now: u64) -> Vec<u64> {
+    let latest_start = candidates.len().saturating_sub(policy.keep_last);
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(i, candidate)| {
+            let recent = policy.keep_days > 0
+                && candidate.created.map_or(true, |created| {
+                    now.saturating_sub(created) < policy.keep_days.saturating_mul(24 * 60 * 60)
+                });
+            !(candidate.protected || *i >= latest_start || recent)
+        })
+        .map(|(_, candidate)| candidate.number)
+        .collect()
+}
+
+/// What garbage collection deleted.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub deleted: Vec<String>,
+    /// Space freed, if btrfs quotas are enabled to measure it.
+    pub freed: Option<u64>,
+}
+
+impl fmt::Display for GcReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.deleted.is_empty() {
+            return writeln!(f, "No roots to delete");
+        }
+        writeln!(f, "Deleted {}", self.deleted.join(", "))?;
+        match self.freed {
+            Some(freed) => writeln!(f, "Freed {}", format_size(freed)),
+            None => writeln!(f, "Freed space is unknown, as btrfs quotas are not enabled"),
+        }
+    }
+}
+
+/// Returns the generations in `generations` as candidates for deletion. The booted, default and
+/// pinned generations are protected.
+fn candidates(generations: &Generations) -> io::Result<Vec<Candidate>> {
+    let pins = Pins::load(generations.top_dir())?;
+    let mut candidates = Vec::new();
+    for generation in generations.iter() {
+        let info = btrfs::subvolume_info(generation.path())?;
+        let protected = pins.contains(generation.subvolid())
+            || generation.subvolid() == generations.booted_subvolid()
+            || generation.subvolid() == generations.default_subvolid();
+        candidates.push(Candidate {
+            number: generation.number(),
+            created: info.created,
+            protected,
+        });
+    }
+    Ok(candidates)
+}
+
+/// Returns the numbers of the generations [`collect_garbage`] would delete with `policy`.
+pub(crate) fn garbage(generations: &Generations, policy: &RetentionPolicy) -> io::Result<Vec<u64>> {
+    Ok(select(&candidates(generations)?, policy, now()))
+}
+
+/// Deletes the generations in `generations` that `policy` does not keep. The booted, default
+/// and pinned generations are always kept, as are named snapshots such as `@root.original`. If
+/// the generation the old loader entry boots is deleted, the entry is pointed at the newest
+/// remaining generation before the default one.
+pub(crate) fn collect_garbage(
+    generations: &Generations,
+    policy: &RetentionPolicy,
+) -> io::Result<GcReport> {
+    let mut report = GcReport {
+        freed: Some(0),
+        ..GcReport::default()
+    };
+    let deleted = garbage(generations, policy)?;
+    for &number in &deleted {
+        let generation = match generations.get(number) {
+            Some(some) => some,
+            None => continue,
+        };
+
+        // Must be read before deleting, and is only an estimate for several deletions as it
+        // does not include space shared by the deleted roots
+        let exclusive = btrfs::qgroup_exclusive(generations.top_dir(), generation.subvolid())?;
+        report.freed = match (report.freed, exclusive) {
+            (Some(freed), Some(exclusive)) => Some(freed + exclusive),
+            _ => None,
+        };
+
+        log::info!("Deleting {}", generation.name());
+        delete_root(generation.path())?;
+        report.deleted.push(generation.name());
+    }
+
+    let old_entry = loader::entry_subvol("Pop_OS-old")?;
+    let old_entry_deleted = old_entry
+        .as_deref()
+        .map_or(false, |name| report.deleted.iter().any(|x| x == name));
+    if let (true, Some(default)) = (old_entry_deleted, generations.default()) {
+        let replacement = generations
+            .iter()
+            .filter(|generation| {
+                generation.number() < default.number() && !deleted.contains(&generation.number())
+            })
+            .last()
+            .unwrap_or(default);
+        log::info!("Pointing old loader entry at {}", replacement.name());
+        loader::set_entry_subvol("Pop_OS-old", &replacement.name())?;
+    }
+
+    Ok(report)
+}
+
+/// Options for [`gc`].
+#[derive(Debug, Default)]
+pub struct GcOptions {
+    /// Overrides `keep-last` of the retention policy.
+    pub keep_last: Option<usize>,
+    /// Overrides `keep-days` of the retention policy.
+    pub keep_days: Option<u64>,
+    /// Wait for another running pop-core to finish, instead of failing.
+    pub wait: bool,
+}
+
+fn gc_with_top_dir(top_dir: &Path, options: &GcOptions) -> io::Result<GcReport> {
+    let mut policy = Config::load(CONFIG_FILE)?.gc;
+    if let Some(keep_last) = options.keep_last {
+        policy.keep_last = keep_last;
+    }
+    if let Some(keep_days) = options.keep_days {
+        policy.keep_days = keep_days;
+    }
+    let generations = load_generations(top_dir)?;
+    collect_garbage(&generations, &policy)
+}
+
+/// Deletes old roots according to the retention policy in [`CONFIG_FILE`].
+pub fn gc(options: &GcOptions) -> io::Result<GcReport> {
+    with_locked_top_dir(options.wait, |top_dir| gc_with_top_dir(top_dir, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    #[test]
+    fn select_generations() {
+        let now = 100 * DAY;
+        let candidates: Vec<Candidate> = (1..=8)
+            .map(|number| Candidate {
+                number,
+                created: Some(now - (10 - number) * DAY),
+                protected: number == 2,
+            })
+            .collect();
+
+        let policy = RetentionPolicy {
+            keep_last: 3,
+            keep_days: 0,
+            auto: true,
+        };
+        assert_eq!(select(&candidates, &policy, now), vec![1, 3, 4, 5]);
+
+        // Generations 4 to 8 are less than 7 days old
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            keep_days: 7,
+            auto: true,
+        };
+        assert_eq!(select(&candidates, &policy, now), vec![1, 3]);
+
+        let policy = RetentionPolicy {
+            keep_last: 20,
+            keep_days: 0,
+            auto: true,
+        };
+        assert!(select(&candidates, &policy, now).is_empty());
+
+        // A huge number of days keeps everything instead of overflowing
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            keep_days: u64::MAX,
+            auto: true,
+        };
+        assert!(select(&candidates, &policy, now).is_empty());
+    }
+}
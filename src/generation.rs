@@ -0,0 +1,349 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{btrfs, loader, util::rename_noreplace, Pins};
+
+/// Prefix of every numbered root subvolume, such as `@root.42`.
+pub const GENERATION_PREFIX: &str = "@root.";
+
+/// Returns the subvolume name of the root generation `number`.
+pub fn generation_name(number: u64) -> String {
+    format!("{}{}", GENERATION_PREFIX, number)
+}
+
+/// Parses a subvolume name like `@root.42` into its generation number.
+///
+/// Named snapshots such as `@root.original` or `@root.new` are not generations.
+pub fn parse_generation_name(name: &str) -> Option<u64> {
+    let suffix = name.strip_prefix(GENERATION_PREFIX)?;
+    if !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()) {
+        suffix.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// A numbered root subvolume in the btrfs top level.
+#[derive(Debug)]
+pub struct Generation {
+    number: u64,
+    path: PathBuf,
+    subvolid: u64,
+}
+
+impl Generation {
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    pub fn name(&self) -> String {
+        generation_name(self.number)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn subvolid(&self) -> u64 {
+        self.subvolid
+    }
+}
+
+/// All root generations found in a btrfs top level, along with which one is the btrfs default
+/// subvolume and which one is currently booted.
+#[derive(Debug)]
+pub struct Generations {
+    top_dir: PathBuf,
+    list: Vec<Generation>,
+    default: Option<u64>,
+    booted: Option<u64>,
+    default_subvolid: u64,
+    booted_subvolid: u64,
+}
+
+impl Generations {
+    /// Scans `top_dir`, which must be the btrfs top level (`subvol=/`), for root generations.
+    /// The generation whose subvolid matches `booted_subvolid` is recorded as booted.
+    pub fn load<P: AsRef<Path>>(top_dir: P, booted_subvolid: u64) -> io::Result<Self> {
+        let top_dir = top_dir.as_ref().to_path_buf();
+
+        let mut list = Vec::new();
+        for entry_res in fs::read_dir(&top_dir)? {
+            let entry = entry_res?;
+            let number = match entry.file_name().to_str().and_then(parse_generation_name) {
+                Some(some) => some,
+                None => continue,
+            };
+            let path = entry.path();
+            let subvolid = btrfs::subvolume_id(&path)?;
+            list.push(Generation {
+                number,
+                path,
+                subvolid,
+            });
+        }
+        list.sort_by_key(|generation| generation.number);
+
+        let default_subvolid = btrfs::default_subvolume_id(&top_dir)?;
+        let default = list
+            .iter()
+            .find(|generation| generation.subvolid == default_subvolid)
+            .map(|generation| generation.number);
+        let booted = list
+            .iter()
+            .find(|generation| generation.subvolid == booted_subvolid)
+            .map(|generation| generation.number);
+
+        Ok(Self {
+            top_dir,
+            list,
+            default,
+            booted,
+            default_subvolid,
+            booted_subvolid,
+        })
+    }
+
+    pub fn top_dir(&self) -> &Path {
+        &self.top_dir
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Generation> {
+        self.list.iter()
+    }
+
+    pub fn get(&self, number: u64) -> Option<&Generation> {
+        self.list
+            .iter()
+            .find(|generation| generation.number == number)
+    }
+
+    /// The generation that is the btrfs default subvolume, and will be booted next.
+    pub fn default(&self) -> Option<&Generation> {
+        self.default.and_then(|number| self.get(number))
+    }
+
+    /// The generation that is currently mounted as `/`.
+    pub fn booted(&self) -> Option<&Generation> {
+        self.booted.and_then(|number| self.get(number))
+    }
+
+    /// The btrfs default subvolid, which may not be a generation.
+    pub fn default_subvolid(&self) -> u64 {
+        self.default_subvolid
+    }
+
+    /// The subvolid mounted as `/`, which may not be a generation.
+    pub fn booted_subvolid(&self) -> u64 {
+        self.booted_subvolid
+    }
+
+    /// The newest generation older than `number`.
+    pub fn before(&self, number: u64) -> Option<&Generation> {
+        self.list
+            .iter()
+            .rev()
+            .find(|generation| generation.number < number)
+    }
+
+    pub fn latest(&self) -> Option<&Generation> {
+        self.list.last()
+    }
+
+    /// The number the next committed generation will use.
+    pub fn next_number(&self) -> u64 {
+        self.latest().map_or(1, |generation| generation.number + 1)
+    }
+
+    /// Finds a root by generation number, generation name, or the name of a snapshot such as
+    /// `@root.original`, returning its name and path. `@root.old` is the generation before the
+    /// default one.
+    pub fn find(&self, name: &str) -> io::Result<(String, PathBuf)> {
+        if let Some(number) = name
+            .parse::<u64>()
+            .ok()
+            .or_else(|| parse_generation_name(name))
+        {
+            return match self.get(number) {
+                Some(generation) => Ok((generation.name(), generation.path().to_path_buf())),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Generation {} not found", number),
+                )),
+            };
+        }
+
+        if name == "@root.old" {
+            if let Some(generation) = self
+                .default()
+                .and_then(|default| self.before(default.number()))
+            {
+                return Ok((generation.name(), generation.path().to_path_buf()));
+            }
+        }
+
+        if !(name == "@root" || name.starts_with("@root.")) || name.contains('/') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid root {:?}", name),
+            ));
+        }
+        let path = self.top_dir.join(name);
+        if !path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found", name),
+            ));
+        }
+        Ok((name.to_string(), path))
+    }
+
+    /// Returns the path a generation numbered `number` has, whether it exists or not.
+    pub fn path(&self, number: u64) -> PathBuf {
+        self.top_dir.join(generation_name(number))
+    }
+}
+
+/// Deletes the root subvolume at `path`, refusing if it is pinned.
+pub(crate) fn delete_root(path: &Path) -> io::Result<()> {
+    let top_dir = path.parent().unwrap_or(path);
+    if Pins::load(top_dir)?.contains(btrfs::subvolume_id(path)?) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is pinned and will not be deleted, unpin it first with: pop-core unpin {}",
+                name, name
+            ),
+        ));
+    }
+    btrfs::delete_subvolume(path)?;
+    Ok(())
+}
+
+/// Renames the fixed `@root` and `@root.old` subvolumes used by older images to the first
+/// generations, if there are no generations yet.
+fn rename_legacy(top_dir: &Path) -> io::Result<()> {
+    for entry_res in fs::read_dir(top_dir)? {
+        let entry = entry_res?;
+        if entry
+            .file_name()
+            .to_str()
+            .and_then(parse_generation_name)
+            .is_some()
+        {
+            return Ok(());
+        }
+    }
+
+    let root = top_dir.join("@root");
+    if !root.exists() {
+        return Ok(());
+    }
+
+    let root_old = top_dir.join("@root.old");
+    if root_old.exists() {
+        log::info!("Moving @root.old to {}", generation_name(0));
+        rename_noreplace(&root_old, top_dir.join(generation_name(0)))?;
+    }
+
+    log::info!("Moving @root to {}", generation_name(1));
+    rename_noreplace(&root, top_dir.join(generation_name(1)))?;
+
+    Ok(())
+}
+
+/// Converts the fixed `@root` and `@root.old` subvolumes used by older images into numbered
+/// generations. Renaming does not change subvolids, so the default and booted roots are kept.
+/// The old loader entry is pointed at what was `@root.old`, or the default if there was none.
+pub fn migrate_legacy<P: AsRef<Path>>(top_dir: P) -> io::Result<()> {
+    let top_dir = top_dir.as_ref();
+
+    rename_legacy(top_dir)?;
+
+    // Checked every time, in case pop-core was interrupted after renaming
+    if loader::entry_subvol("Pop_OS-old")?.as_deref() == Some("@root.old")
+        && !top_dir.join("@root.old").exists()
+    {
+        let name = [0, 1]
+            .iter()
+            .map(|number| generation_name(*number))
+            .find(|name| top_dir.join(name).exists());
+        if let Some(name) = name {
+            log::info!("Pointing old loader entry at {}", name);
+            loader::set_entry_subvol("Pop_OS-old", &name)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::create_temp_dir;
+
+    #[test]
+    fn generation_names() {
+        assert_eq!(parse_generation_name("@root.0"), Some(0));
+        assert_eq!(parse_generation_name("@root.42"), Some(42));
+        assert_eq!(parse_generation_name(&generation_name(7)), Some(7));
+        assert_eq!(parse_generation_name("@root"), None);
+        assert_eq!(parse_generation_name("@root."), None);
+        assert_eq!(parse_generation_name("@root.new"), None);
+        assert_eq!(parse_generation_name("@root.old"), None);
+        assert_eq!(parse_generation_name("@root.original"), None);
+        assert_eq!(parse_generation_name("@root.+1"), None);
+        assert_eq!(parse_generation_name("@root.1a"), None);
+        assert_eq!(parse_generation_name("@home.1"), None);
+        assert_eq!(parse_generation_name("@root.99999999999999999999"), None);
+    }
+
+    #[test]
+    fn rename_legacy_layout() {
+        let dir = create_temp_dir("pop-core-generation").unwrap();
+        for name in &["@root", "@root.old", "@root.original", "@home"] {
+            fs::create_dir(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("name"), name).unwrap();
+        }
+
+        rename_legacy(&dir).unwrap();
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["@home", "@root.0", "@root.1", "@root.original"]);
+        assert_eq!(
+            fs::read_to_string(dir.join("@root.0/name")).unwrap(),
+            "@root.old"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("@root.1/name")).unwrap(),
+            "@root"
+        );
+
+        // Once there are generations, a new @root is left alone
+        fs::create_dir(dir.join("@root")).unwrap();
+        rename_legacy(&dir).unwrap();
+        assert!(dir.join("@root").exists());
+        assert!(!dir.join("@root.2").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rename_legacy_without_old() {
+        let dir = create_temp_dir("pop-core-generation").unwrap();
+        fs::create_dir(dir.join("@root")).unwrap();
+
+        rename_legacy(&dir).unwrap();
+        assert!(!dir.join("@root").exists());
+        assert!(!dir.join("@root.0").exists());
+        assert!(dir.join("@root.1").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::util::status_ignoring_interrupts;
+
+/// Directory with a subdirectory of hooks for each [`HookStage`].
+pub const HOOKS_DIR: &str = "/etc/pop-core/hooks.d";
+
+/// Hooks with this suffix run inside the container instead of on the host.
+const CONTAINER_SUFFIX: &str = ".container";
+
+/// Where a container hook is bind mounted inside the container.
+const CONTAINER_HOOK_PATH: &str = "/run/pop-core-hook";
+
+/// When hooks run during an update.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum HookStage {
+    /// After `@root.new` is snapshotted, before the command runs.
+    Pre,
+    /// After the command succeeds, before `@root.new` is made read-only.
+    PostCommand,
+    /// After the new generation is the default subvolume. It is read-only and the update can no
+    /// longer be undone, so these hooks cannot abort it and failures are only warned about.
+    PostCommit,
+}
+
+impl HookStage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pre => "pre",
+            Self::PostCommand => "post-command",
+            Self::PostCommit => "post-commit",
+        }
+    }
+}
+
+/// Executables run around an update, in order of file name. Names starting with `.` or ending
+/// with `~` are ignored.
+pub(crate) struct Hooks {
+    dir: PathBuf,
+}
+
+impl Hooks {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the hooks for `stage`, sorted by file name.
+    pub fn list(&self, stage: HookStage) -> io::Result<Vec<PathBuf>> {
+        let stage_dir = self.dir.join(stage.name());
+        let entries = match fs::read_dir(&stage_dir) {
+            Ok(ok) => ok,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut hooks = Vec::new();
+        for entry_res in entries {
+            let path = entry_res?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(some) => some,
+                None => continue,
+            };
+            if name.starts_with('.') || name.ends_with('~') {
+                continue;
+            }
+
+            let metadata = fs::metadata(&path)?;
+            if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+                log::debug!("Skipping non-executable hook {}", path.display());
+                continue;
+            }
+            hooks.push(path);
+        }
+        hooks.sort();
+        Ok(hooks)
+    }
+
+    /// Runs every hook for `stage` with `env` set, stopping at the first that fails. Container
+    /// hooks are run with the command returned by `container`, which must be a
+    /// `systemd-nspawn` command without the program to run.
+    pub fn run<C: FnMut() -> io::Result<Command>>(
+        &self,
+        stage: HookStage,
+        env: &[(&str, String)],
+        mut container: C,
+    ) -> io::Result<()> {
+        for hook in self.list(stage)? {
+            let in_container = hook
+                .to_str()
+                .map_or(false, |path| path.ends_with(CONTAINER_SUFFIX));
+
+            let mut command = if in_container {
+                log::info!(
+                    "Running {} hook {} in container",
+                    stage.name(),
+                    hook.display()
+                );
+                let mut command = container()?;
+                command.arg(format!(
+                    "--bind-ro={}:{}",
+                    hook.display(),
+                    CONTAINER_HOOK_PATH
+                ));
+                for (key, value) in env {
+                    command.arg(format!("--setenv={}={}", key, value));
+                }
+                command.arg("--").arg(CONTAINER_HOOK_PATH);
+                command
+            } else {
+                log::info!("Running {} hook {}", stage.name(), hook.display());
+                let mut command = Command::new(&hook);
+                command.envs(env.iter().map(|(key, value)| (key, value)));
+                command
+            };
+
+            let status = status_ignoring_interrupts(&mut command)?;
+            if !status.success() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "{} hook {} failed with {}",
+                        stage.name(),
+                        hook.display(),
+                        status
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn write_hook(path: &Path, script: &str, mode: u32) {
+        fs::write(path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn run_host_hooks() {
+        let dir = env::temp_dir().join(format!("pop-core-hooks-test.{}", process::id()));
+        let pre_dir = dir.join("pre");
+        fs::create_dir_all(&pre_dir).unwrap();
+        let out = dir.join("out");
+
+        write_hook(
+            &pre_dir.join("20-second"),
+            &format!("echo \"second $POP_CORE_TEST\" >> {}", out.display()),
+            0o755,
+        );
+        write_hook(
+            &pre_dir.join("10-first"),
+            &format!("echo first >> {}", out.display()),
+            0o755,
+        );
+        write_hook(&pre_dir.join("30-not-executable"), "exit 1", 0o644);
+        write_hook(&pre_dir.join(".hidden"), "exit 1", 0o755);
+        write_hook(&pre_dir.join("40-backup~"), "exit 1", 0o755);
+
+        let hooks = Hooks::new(&dir);
+        assert_eq!(
+            hooks.list(HookStage::Pre).unwrap(),
+            vec![pre_dir.join("10-first"), pre_dir.join("20-second")]
+        );
+        assert!(hooks.list(HookStage::PostCommit).unwrap().is_empty());
+
+        let no_container = || -> io::Result<Command> { panic!("no container hooks") };
+        hooks
+            .run(
+                HookStage::Pre,
+                &[("POP_CORE_TEST", "value".to_string())],
+                no_container,
+            )
+            .unwrap();
+        assert_eq!(fs::read_to_string(&out).unwrap(), "first\nsecond value\n");
+
+        write_hook(&pre_dir.join("15-fail"), "exit 3", 0o755);
+        let err = hooks.run(HookStage::Pre, &[], no_container).unwrap_err();
+        assert!(err.to_string().contains("15-fail"));
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "first\nsecond value\nfirst\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use std::{fs, io, path::Path, process::Command};
+
+use crate::{
+    btrfs, util::status_ignoring_interrupts, with_locked_top_dir, CommandFailed, Generations,
+};
+
+/// Returns a `systemd-nspawn` command for a read-only container of `root_dir`, sharing `/home`
+/// and `/var` with the host read-only too, without the program to run.
+fn inspect_command(root_dir: &Path, hostname: &str) -> Command {
+    let mut nspawn = Command::new("systemd-nspawn");
+    nspawn
+        .arg("--bind-ro=/home")
+        .arg("--bind-ro=/run/systemd/resolve/stub-resolv.conf")
+        .arg("--bind-ro=/var")
+        .arg(format!("--directory={}", root_dir.display()))
+        .arg("--link-journal=no")
+        .arg(format!("--machine={}", hostname))
+        .arg("--quiet")
+        .arg("--read-only")
+        .arg("--resolv-conf=off")
+        .arg("--timezone=off");
+    nspawn
+}
+
+fn inspect_with_top_dir(top_dir: &Path, target: &str, command: &[String]) -> io::Result<()> {
+    log::debug!("Getting hostname");
+    let hostname = fs::read_to_string("/etc/hostname")?.trim().to_string();
+
+    // Like diff, this only reads, so it does not migrate or recover
+    let generations = Generations::load(top_dir, btrfs::subvolume_id("/")?)?;
+    let (name, root_dir) = generations.find(target)?;
+
+    log::info!("Starting read-only container of {}", name);
+    let mut nspawn = inspect_command(&root_dir, &hostname);
+    if !command.is_empty() {
+        nspawn.arg("--").args(command);
+    }
+    let status = status_ignoring_interrupts(&mut nspawn)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, CommandFailed(status)))
+    }
+}
+
+/// Runs `command`, or a shell if it is empty, in a read-only container of a root, with `/home`
+/// and `/var` of the host mounted read-only. Nothing is snapshotted or committed. A failed
+/// command is returned as a [`CommandFailed`] error. The lock is held until the container exits,
+/// so the root cannot be deleted meanwhile. If another pop-core is running, this waits for it if
+/// `wait` is true, and fails otherwise.
+pub fn inspect(target: String, command: Vec<String>, wait: bool) -> io::Result<()> {
+    with_locked_top_dir(wait, |top_dir| {
+        inspect_with_top_dir(top_dir, &target, &command)
+    })
+}
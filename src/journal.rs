@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::UpdateStep;
+
+/// Progress of an update, saved after every step so an interrupted update can be recovered.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct JournalEntry {
+    /// Generation number the update was snapshotted from.
+    pub parent: u64,
+    /// Generation number the update will be promoted to.
+    pub root: u64,
+    /// The last step that finished, if any.
+    pub completed: Option<UpdateStep>,
+    /// The update was abandoned, so `@root.new` is deleted instead of committed. Written before
+    /// anything is deleted, so recovery never commits it.
+    #[serde(default)]
+    pub discarded: bool,
+}
+
+/// The update journal, stored in the btrfs top level so it is seen by every root.
+pub(crate) struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(top_dir: &Path) -> Self {
+        Self {
+            path: top_dir.join("pop-core.journal"),
+        }
+    }
+
+    pub fn read(&self) -> io::Result<Option<JournalEntry>> {
+        let data = match fs::read(&self.path) {
+            Ok(ok) => ok,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        serde_json::from_slice(&data).map(Some).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("failed to parse {}: {}", self.path.display(), err),
+            )
+        })
+    }
+
+    /// Replaces the journal with `entry`, making sure it is on disk before returning.
+    pub fn write(&self, entry: &JournalEntry) -> io::Result<()> {
+        let data = serde_json::to_vec(entry)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let partial_path = self.path.with_extension("journal.partial");
+        let mut file = File::create(&partial_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&partial_path, &self.path)?;
+        self.sync_dir()
+    }
+
+    pub fn remove(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => self.sync_dir(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn sync_dir(&self) -> io::Result<()> {
+        match self.path.parent() {
+            Some(parent) => File::open(parent)?.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
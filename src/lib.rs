@@ -1,19 +1,73 @@
+pub mod btrfs;
+
 pub use self::build::*;
 mod build;
 
 pub use self::cache::*;
 mod cache;
 
+pub use self::command_log::*;
+mod command_log;
+
+pub use self::config::*;
+mod config;
+
 pub use self::debootstrap::*;
 mod debootstrap;
 
+pub use self::diff::*;
+mod diff;
+
+pub mod dpkg;
+
+pub use self::factory_reset::*;
+mod factory_reset;
+
+pub use self::gc::*;
+mod gc;
+
+pub use self::generation::*;
+mod generation;
+
+pub use self::hooks::*;
+mod hooks;
+
+pub use self::inspect::*;
+mod inspect;
+
+pub mod loader;
+
+pub use self::lock::*;
+mod lock;
+
+use self::journal::*;
+mod journal;
+
 pub use self::loopback::*;
 mod loopback;
 
+pub use self::manifest::*;
+mod manifest;
+
+pub use self::metadata::*;
+mod metadata;
+
 pub use self::mount::*;
 mod mount;
 
+pub use self::mountinfo::*;
+mod mountinfo;
+
+pub use self::pin::*;
+mod pin;
+
+pub use self::rollback::*;
+mod rollback;
+
 pub use self::run::*;
 mod run;
 
+pub use self::status::*;
+mod status;
+
 pub mod util;
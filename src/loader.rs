@@ -0,0 +1,98 @@
+use std::{fs, io, path::Path};
+
+/// Where systemd-boot entries are found on the running system.
+pub const LOADER_ENTRIES_DIR: &str = "/boot/efi/loader/entries";
+
+/// Points the loader entry `name` at the root subvolume `subvol`, by replacing the
+/// `rootflags=subvol=` option and the subvolume shown in its title.
+///
+/// Does nothing if the entry does not exist, such as when the ESP is not mounted.
+pub fn set_entry_subvol(name: &str, subvol: &str) -> io::Result<()> {
+    let path = Path::new(LOADER_ENTRIES_DIR).join(format!("{}.conf", name));
+    if !path.exists() {
+        log::debug!("Loader entry {} not found", path.display());
+        return Ok(());
+    }
+
+    let data = fs::read_to_string(&path)?;
+    let mut new_data = String::with_capacity(data.len());
+    for line in data.lines() {
+        if line.starts_with("title ") {
+            new_data.push_str(&format!("title Pop!_OS ({})", subvol));
+        } else if let Some(options) = line.strip_prefix("options ") {
+            new_data.push_str("options");
+            for option in options.split_whitespace() {
+                new_data.push(' ');
+                if option.starts_with("rootflags=") {
+                    new_data.push_str(&format!("rootflags=subvol={}", subvol));
+                } else {
+                    new_data.push_str(option);
+                }
+            }
+        } else {
+            new_data.push_str(line);
+        }
+        new_data.push('\n');
+    }
+
+    log::debug!("Pointing loader entry {} at {}", name, subvol);
+    let partial_path = path.with_extension("conf.partial");
+    fs::write(&partial_path, new_data)?;
+    fs::rename(&partial_path, &path)
+}
+
+/// Returns the root subvolume the loader entry `name` boots, from its `rootflags=subvol=`
+/// option, or `None` if the entry or option does not exist.
+pub fn entry_subvol(name: &str) -> io::Result<Option<String>> {
+    let path = Path::new(LOADER_ENTRIES_DIR).join(format!("{}.conf", name));
+    let data = match fs::read_to_string(&path) {
+        Ok(ok) => ok,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    for line in data.lines() {
+        if let Some(options) = line.strip_prefix("options ") {
+            for option in options.split_whitespace() {
+                if let Some(subvol) = option.strip_prefix("rootflags=subvol=") {
+                    return Ok(Some(subvol.trim_start_matches('/').to_string()));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The systemd-boot configuration on the running system.
+pub const LOADER_CONF: &str = "/boot/efi/loader/loader.conf";
+
+/// Makes `name` the entry systemd-boot selects by default.
+///
+/// Does nothing if the configuration does not exist, such as when the ESP is not mounted.
+pub fn set_default_entry(name: &str) -> io::Result<()> {
+    let path = Path::new(LOADER_CONF);
+    if !path.exists() {
+        log::debug!("Loader configuration {} not found", path.display());
+        return Ok(());
+    }
+
+    let data = fs::read_to_string(path)?;
+    let mut new_data = String::with_capacity(data.len());
+    let mut found = false;
+    for line in data.lines() {
+        if line.starts_with("default ") {
+            new_data.push_str(&format!("default {}", name));
+            found = true;
+        } else {
+            new_data.push_str(line);
+        }
+        new_data.push('\n');
+    }
+    if !found {
+        new_data.push_str(&format!("default {}\n", name));
+    }
+
+    log::debug!("Setting default loader entry to {}", name);
+    let partial_path = path.with_extension("conf.partial");
+    fs::write(&partial_path, new_data)?;
+    fs::rename(&partial_path, path)
+}
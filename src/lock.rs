@@ -0,0 +1,125 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::Path,
+    process,
+};
+
+/// Lock held while changing roots, so only one pop-core does changes at a time.
+pub const LOCK_FILE: &str = "/run/pop-core.lock";
+
+/// Returns true if a process with this PID exists.
+fn pid_running(pid: i32) -> bool {
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// An exclusive `flock` on a file holding the PID of the owner. The lock is released by the
+/// kernel when the file is closed, so a crashed pop-core never leaves it held.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+}
+
+impl Lock {
+    /// Takes the lock at `path`. If another process holds it, this fails with `WouldBlock`, or
+    /// waits for it to be released if `wait` is true.
+    pub fn acquire<P: AsRef<Path>>(path: P, wait: bool) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::WouldBlock {
+                return Err(err);
+            }
+
+            let holder = match Self::read_pid(&mut file)? {
+                Some(pid) if pid_running(pid) => format!("PID {}", pid),
+                Some(pid) => format!("PID {}, which has exited, or a process it started", pid),
+                None => "another process".to_string(),
+            };
+            if !wait {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "{} is held by {}, is pop-core already running? Use --wait to wait for it",
+                        path.display(),
+                        holder
+                    ),
+                ));
+            }
+
+            log::info!("Waiting for {} held by {}", path.display(), holder);
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // A PID left in the file is from a holder that exited without releasing it cleanly
+        if let Some(pid) = Self::read_pid(&mut file)? {
+            log::warn!("Taking over stale lock {} from PID {}", path.display(), pid);
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", process::id())?;
+
+        Ok(Self { file })
+    }
+
+    fn read_pid(file: &mut File) -> io::Result<Option<i32>> {
+        let mut data = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut data)?;
+        Ok(data.trim().parse().ok())
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // The file is not removed, as a waiting process may already have it open
+        if let Err(err) = self.file.set_len(0) {
+            log::error!("Failed to clear lock file: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn exclusive_lock() {
+        let path = env::temp_dir().join(format!("pop-core-lock-test.{}", process::id()));
+
+        let lock = Lock::acquire(&path, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        let err = Lock::acquire(&path, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(err.to_string().contains(&format!("PID {}", process::id())));
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        // A PID left behind by a crash does not block the lock
+        fs::write(&path, "1\n").unwrap();
+        drop(Lock::acquire(&path, false).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
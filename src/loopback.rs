@@ -34,7 +34,7 @@ impl Loopback {
             })?;
 
             Ok(Loopback {
-                file: file,
+                file,
                 device: PathBuf::from(device.trim()),
                 attached: true,
             })
@@ -60,10 +60,7 @@ impl Loopback {
             self.detach()?;
             res
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                format!("loopback device not attached"),
-            ))
+            Err(Error::new(ErrorKind::Other, "loopback device not attached"))
         }
     }
 
@@ -96,3 +93,34 @@ impl Drop for Loopback {
         self.detach().expect("Loopback::drop");
     }
 }
+
+/// Runs `function` with the directory a new btrfs filesystem is mounted on, from an image file
+/// that is removed afterwards. Needs root, btrfs-progs and losetup, so tests using this are
+/// ignored by default.
+#[cfg(test)]
+pub(crate) fn with_test_btrfs<T, F: FnOnce(&Path) -> Result<T>>(function: F) -> Result<T> {
+    use crate::{util::check_status, Mount};
+    use std::fs::{self, File};
+
+    fn mount_image<T, F: FnOnce(&Path) -> Result<T>>(dir: &Path, function: F) -> Result<T> {
+        let image_file = dir.join("image.raw");
+        File::create(&image_file)?.set_len(256 * 1024 * 1024)?;
+        Command::new("mkfs.btrfs")
+            .arg("--quiet")
+            .arg(&image_file)
+            .status()
+            .and_then(check_status)?;
+
+        let mount_dir = dir.join("mount");
+        fs::create_dir(&mount_dir)?;
+        Loopback::new(&image_file)?.with(|loopback| {
+            Mount::new(loopback.device(), &mount_dir, "btrfs", 0, None)?
+                .with(|_mount| function(&mount_dir))
+        })
+    }
+
+    let dir = crate::util::create_temp_dir("pop-core-btrfs")?;
+    let res = mount_image(&dir, function);
+    fs::remove_dir_all(&dir)?;
+    res
+}
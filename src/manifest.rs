@@ -0,0 +1,574 @@
+use serde::Deserialize;
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// How the base system is bootstrapped.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DebootstrapManifest {
+    pub suite: String,
+    pub arch: String,
+    pub mirror: String,
+    pub variant: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Default for DebootstrapManifest {
+    fn default() -> Self {
+        Self {
+            suite: "jammy".to_string(),
+            arch: "amd64".to_string(),
+            mirror: "https://apt.pop-os.org/ubuntu".to_string(),
+            variant: Some("minbase".to_string()),
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// A file written into the root, from a `file` next to the manifest or inline `contents`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Overlay {
+    /// Path inside the root, relative to `/`.
+    pub path: PathBuf,
+    /// File to copy, relative to the directory of the manifest.
+    pub file: Option<PathBuf>,
+    pub contents: Option<String>,
+    /// Built into pop-core-build, for the defaults.
+    #[serde(skip)]
+    embedded: Option<&'static [u8]>,
+}
+
+impl Overlay {
+    fn embedded(path: &str, data: &'static [u8]) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            embedded: Some(data),
+            ..Self::default()
+        }
+    }
+
+    /// Reads the data to write, resolving `file` relative to `base_dir`.
+    pub fn data(&self, base_dir: &Path) -> io::Result<Cow<'static, [u8]>> {
+        match (&self.file, &self.contents, self.embedded) {
+            (Some(file), _, _) => {
+                let file = base_dir.join(file);
+                fs::read(&file).map(Cow::Owned).map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("failed to read {}: {}", file.display(), err),
+                    )
+                })
+            }
+            (None, Some(contents), _) => Ok(Cow::Owned(contents.clone().into_bytes())),
+            (None, None, Some(embedded)) => Ok(Cow::Borrowed(embedded)),
+            (None, None, None) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("overlay {} has no file or contents", self.path.display()),
+            )),
+        }
+    }
+
+    /// Writes the overlay into `root_dir`, creating parent directories.
+    pub fn write(&self, root_dir: &Path, base_dir: &Path) -> io::Result<()> {
+        let path = root_dir.join(&self.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, self.data(base_dir)?)
+    }
+}
+
+/// An APT repository, written to `/etc/apt/sources.list.d/{name}.sources` in deb822 format.
+/// Keyrings it needs are added as overlays.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Repository {
+    pub name: String,
+    /// Sources file to copy, relative to the directory of the manifest.
+    pub file: Option<PathBuf>,
+    pub contents: Option<String>,
+    #[serde(skip)]
+    embedded: Option<&'static [u8]>,
+}
+
+impl Repository {
+    fn embedded(name: &str, data: &'static [u8]) -> Self {
+        Self {
+            name: name.to_string(),
+            embedded: Some(data),
+            ..Self::default()
+        }
+    }
+
+    /// Returns the sources file of the repository as an overlay.
+    pub fn overlay(&self) -> Overlay {
+        Overlay {
+            path: Path::new("etc/apt/sources.list.d").join(format!("{}.sources", self.name)),
+            file: self.file.clone(),
+            contents: self.contents.clone(),
+            embedded: self.embedded,
+        }
+    }
+}
+
+/// A stage that installs packages on top of the previous one.
+#[derive(Clone, Debug, Default)]
+pub struct PackageStage {
+    /// Packages to install, in addition to those of earlier stages.
+    pub packages: Vec<String>,
+    /// Repositories added before installing packages.
+    pub repositories: Vec<Repository>,
+    /// Files written before installing packages, after repositories.
+    pub overlays: Vec<Overlay>,
+}
+
+/// A stage as written in a manifest, where each setting left out keeps its default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct PackageStageFile {
+    packages: Option<Vec<String>>,
+    repositories: Option<Vec<Repository>>,
+    overlays: Option<Vec<Overlay>>,
+}
+
+impl PackageStageFile {
+    fn or(self, default: PackageStage) -> PackageStage {
+        PackageStage {
+            packages: self.packages.unwrap_or(default.packages),
+            repositories: self.repositories.unwrap_or(default.repositories),
+            overlays: self.overlays.unwrap_or(default.overlays),
+        }
+    }
+}
+
+impl PackageStage {
+    fn default_server() -> Self {
+        Self {
+            packages: [
+                "binutils", // for unified kernel image
+                "btrfs-progs",
+                "kernelstub",
+                "linux-system76",
+                "network-manager",
+                "pop-default-settings",
+                "shim-signed", // for secure boot
+                "systemd-container",
+            ]
+            .iter()
+            .map(|package| package.to_string())
+            .collect(),
+            repositories: vec![
+                Repository::embedded(
+                    "system",
+                    include_bytes!("../res/etc/apt/sources.list.d/system.sources"),
+                ),
+                Repository::embedded(
+                    "pop-os-release",
+                    include_bytes!("../res/etc/apt/sources.list.d/pop-os-release.sources"),
+                ),
+                // The image has always used the release sources for applications too
+                Repository::embedded(
+                    "pop-os-apps",
+                    include_bytes!("../res/etc/apt/sources.list.d/pop-os-release.sources"),
+                ),
+            ],
+            overlays: vec![
+                Overlay::embedded("etc/hostname", include_bytes!("../res/etc/hostname")),
+                Overlay::embedded(
+                    "etc/apt/sources.list",
+                    include_bytes!("../res/etc/apt/sources.list"),
+                ),
+                Overlay::embedded(
+                    "etc/apt/trusted.gpg.d/pop-keyring-2017-archive.gpg",
+                    include_bytes!("../res/etc/apt/trusted.gpg.d/pop-keyring-2017-archive.gpg"),
+                ),
+                Overlay::embedded(
+                    "etc/kernelstub/configuration",
+                    include_bytes!("../res/etc/kernelstub/configuration"),
+                ),
+            ],
+        }
+    }
+
+    fn default_desktop() -> Self {
+        Self {
+            packages: [
+                "alacritty",
+                "cosmic-session",
+                "flatpak",
+                "libegl1",         // cosmic-comp dependency
+                "libgl1-mesa-dri", // cosmic-comp dependency
+                "libglib2.0-bin",  // for gsettings command
+                "pop-gtk-theme",
+                "pop-icon-theme",
+                "pop-wallpapers",
+                "wireplumber",
+            ]
+            .iter()
+            .map(|package| package.to_string())
+            .collect(),
+            repositories: Vec::new(),
+            overlays: Vec::new(),
+        }
+    }
+
+    /// Writes the repositories and overlays into `root_dir`.
+    pub fn write_files(&self, root_dir: &Path, base_dir: &Path) -> io::Result<()> {
+        for repository in &self.repositories {
+            log::info!("Adding {} APT repository", repository.name);
+            repository.overlay().write(root_dir, base_dir)?;
+        }
+        for overlay in &self.overlays {
+            log::info!("Writing /{}", overlay.path.display());
+            overlay.write(root_dir, base_dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// The layout of the disk image.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ImageManifest {
+    /// Size of the image, like `32GiB`.
+    pub size: String,
+    /// Size of the EFI system partition, like `512MiB`.
+    pub esp_size: String,
+    /// Directories stored in their own top level subvolume, like `@home` for `home`, so that
+    /// they are shared by all roots.
+    pub subvolumes: Vec<String>,
+}
+
+impl Default for ImageManifest {
+    fn default() -> Self {
+        Self {
+            size: "32GiB".to_string(),
+            esp_size: "512MiB".to_string(),
+            subvolumes: vec!["home".to_string(), "tmp".to_string(), "var".to_string()],
+        }
+    }
+}
+
+/// Parses a size in bytes with an optional binary unit, like `512MiB` or `32G`.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let shift = match unit.trim() {
+        "" | "B" => 0,
+        "K" | "KiB" => 10,
+        "M" | "MiB" => 20,
+        "G" | "GiB" => 30,
+        "T" | "TiB" => 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Describes the image built by `pop-core-build`, read from a TOML file. Every setting has a
+/// default that builds the Pop!_OS desktop image, so a manifest only needs what it changes.
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    pub debootstrap: DebootstrapManifest,
+    pub server: PackageStage,
+    pub desktop: PackageStage,
+    pub image: ImageManifest,
+    /// Directory that overlay and repository files are relative to.
+    pub base_dir: PathBuf,
+}
+
+/// A manifest as written in TOML.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ManifestFile {
+    debootstrap: DebootstrapManifest,
+    server: PackageStageFile,
+    desktop: PackageStageFile,
+    image: ImageManifest,
+}
+
+/// Returns true if `path` is relative and stays inside the directory it is relative to.
+fn is_inside(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn is_package_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.:=~".contains(c))
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            debootstrap: DebootstrapManifest::default(),
+            server: PackageStage::default_server(),
+            desktop: PackageStage::default_desktop(),
+            image: ImageManifest::default(),
+            base_dir: PathBuf::from("."),
+        }
+    }
+}
+
+impl Manifest {
+    /// Parses a manifest, with files relative to `base_dir`. It still needs to be validated.
+    pub fn parse(data: &str, base_dir: &Path) -> io::Result<Self> {
+        let file: ManifestFile =
+            toml::from_str(data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Self {
+            debootstrap: file.debootstrap,
+            server: file.server.or(PackageStage::default_server()),
+            desktop: file.desktop.or(PackageStage::default_desktop()),
+            image: file.image,
+            base_dir: base_dir.to_path_buf(),
+        })
+    }
+
+    /// Reads and validates the manifest at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let context = |err: io::Error| {
+            io::Error::new(
+                err.kind(),
+                format!("invalid manifest {}: {}", path.display(), err),
+            )
+        };
+        let data = fs::read_to_string(path).map_err(context)?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let manifest = Self::parse(&data, base_dir).map_err(context)?;
+        manifest.validate().map_err(context)?;
+        Ok(manifest)
+    }
+
+    /// Packages installed by the server stage.
+    pub fn server_packages(&self) -> Vec<String> {
+        self.server.packages.clone()
+    }
+
+    /// Packages installed by the desktop stage, which includes those of the server stage so
+    /// that they stay manually installed.
+    pub fn desktop_packages(&self) -> Vec<String> {
+        let mut packages = self.server_packages();
+        packages.extend(self.desktop.packages.iter().cloned());
+        packages
+    }
+
+    /// Returns the size of the image and of its EFI system partition, in bytes.
+    pub fn image_sizes(&self) -> io::Result<(u64, u64)> {
+        let parse = |name: &str, size: &str| {
+            parse_size(size).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "image.{}: invalid size {:?}, use a size like 512MiB",
+                        name, size
+                    ),
+                )
+            })
+        };
+        Ok((
+            parse("size", &self.image.size)?,
+            parse("esp-size", &self.image.esp_size)?,
+        ))
+    }
+
+    /// Checks the manifest, returning every problem at once so that a build does not start
+    /// only to fail in a later stage.
+    pub fn validate(&self) -> io::Result<()> {
+        let mut errors = Vec::new();
+
+        let debootstrap = &self.debootstrap;
+        for (name, value) in [
+            ("suite", &debootstrap.suite),
+            ("arch", &debootstrap.arch),
+            ("mirror", &debootstrap.mirror),
+        ] {
+            if value.trim().is_empty() {
+                errors.push(format!("debootstrap.{}: must not be empty", name));
+            }
+        }
+        for (name, packages) in [
+            ("include", &debootstrap.include),
+            ("exclude", &debootstrap.exclude),
+        ] {
+            for package in packages {
+                if !is_package_name(package) || package.contains([':', '=']) {
+                    errors.push(format!(
+                        "debootstrap.{}: invalid package name {:?}",
+                        name, package
+                    ));
+                }
+            }
+        }
+
+        for (stage_name, stage) in [("server", &self.server), ("desktop", &self.desktop)] {
+            for package in &stage.packages {
+                if !is_package_name(package) {
+                    errors.push(format!(
+                        "{}.packages: invalid package name {:?}",
+                        stage_name, package
+                    ));
+                }
+            }
+
+            let mut names = BTreeSet::new();
+            for repository in &stage.repositories {
+                let valid = !repository.name.is_empty()
+                    && repository
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+                    && !repository.name.starts_with('.');
+                if !valid {
+                    errors.push(format!(
+                        "{}.repositories: invalid name {:?}",
+                        stage_name, repository.name
+                    ));
+                } else if !names.insert(&repository.name) {
+                    errors.push(format!(
+                        "{}.repositories: {} is defined twice",
+                        stage_name, repository.name
+                    ));
+                }
+            }
+
+            for overlay in stage
+                .repositories
+                .iter()
+                .map(Repository::overlay)
+                .chain(stage.overlays.iter().cloned())
+            {
+                let what = format!("{}: /{}", stage_name, overlay.path.display());
+                if !is_inside(&overlay.path) {
+                    errors.push(format!(
+                        "{}.overlays: path {:?} must be relative, without . or ..",
+                        stage_name, overlay.path
+                    ));
+                    continue;
+                }
+                match (&overlay.file, &overlay.contents, overlay.embedded) {
+                    (Some(_), Some(_), _) => {
+                        errors.push(format!("{}: has both file and contents", what));
+                    }
+                    (Some(file), None, _) => {
+                        let file = self.base_dir.join(file);
+                        if !file.is_file() {
+                            errors.push(format!("{}: file {} not found", what, file.display()));
+                        }
+                    }
+                    (None, None, None) => {
+                        errors.push(format!("{}: needs a file or contents", what));
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        match self.image_sizes() {
+            Ok((size, esp_size)) => {
+                if esp_size < 32 * 1024 * 1024 {
+                    errors.push("image.esp-size: must be at least 32MiB".to_string());
+                }
+                if esp_size >= size {
+                    errors.push("image.esp-size: must be smaller than image.size".to_string());
+                }
+            }
+            Err(err) => errors.push(err.to_string()),
+        }
+        let mut subvolumes = BTreeSet::new();
+        for subvolume in &self.image.subvolumes {
+            if !is_inside(Path::new(subvolume)) || subvolume.contains(['/', ' ']) {
+                errors.push(format!(
+                    "image.subvolumes: {:?} must be a single directory name",
+                    subvolume
+                ));
+            } else if !subvolumes.insert(subvolume) {
+                errors.push(format!("image.subvolumes: {} is listed twice", subvolume));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("\n  {}", errors.join("\n  ")),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512MiB"), Some(512 * 1024 * 1024));
+        assert_eq!(parse_size("32G"), Some(32 << 30));
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("1.5GiB"), None);
+        assert_eq!(parse_size("GiB"), None);
+    }
+
+    #[test]
+    fn defaults() {
+        let manifest = Manifest::parse("", Path::new(".")).unwrap();
+        manifest.validate().unwrap();
+        assert_eq!(manifest.debootstrap.suite, "jammy");
+        assert_eq!(manifest.server.repositories.len(), 3);
+        assert!(manifest
+            .desktop_packages()
+            .contains(&"linux-system76".to_string()));
+        assert_eq!(
+            manifest.image_sizes().unwrap(),
+            (32 << 30, 512 * 1024 * 1024)
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let manifest = Manifest::parse(
+            r#"
+[desktop]
+packages = ["Bad Name"]
+overlays = [{ path = "/etc/issue", contents = "Pop" }]
+
+[image]
+esp-size = "1TiB"
+subvolumes = ["home", "home"]
+"#,
+            Path::new("."),
+        )
+        .unwrap();
+        let err = manifest.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("desktop.packages: invalid package name"),
+            "{}",
+            err
+        );
+        assert!(err.contains("must be relative"), "{}", err);
+        assert!(err.contains("must be smaller than image.size"), "{}", err);
+        assert!(err.contains("home is listed twice"), "{}", err);
+
+        assert!(Manifest::parse("[server]\npackage = []\n", Path::new(".")).is_err());
+
+        // Settings left out of a stage keep their defaults
+        let manifest = Manifest::parse("[server]\npackages = [\"vim\"]\n", Path::new(".")).unwrap();
+        assert_eq!(manifest.server_packages(), vec!["vim".to_string()]);
+        assert_eq!(manifest.server.repositories.len(), 3);
+    }
+}
@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use std::{env, fs, io, path::Path};
+
+use crate::{btrfs, util::user_name};
+
+/// Location of the metadata inside a root.
+pub const METADATA_FILE: &str = "usr/lib/pop-core/generation.json";
+
+/// How a root was created by `pop-core run`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RunRecord {
+    pub command: String,
+    pub args: Vec<String>,
+    /// The user who ran pop-core through `sudo` or `pkexec`.
+    pub user: Option<String>,
+    pub uid: Option<u32>,
+    /// Start time of the command, in seconds since the epoch.
+    pub started: u64,
+    /// End time of the command, in seconds since the epoch.
+    pub finished: u64,
+    pub parent: String,
+    pub parent_subvolid: u64,
+    /// Exit code of the command, 128 plus the signal number if it was killed.
+    pub exit_code: i32,
+    /// Version of pop-core that wrote the record.
+    pub version: String,
+    /// The output of the command is stored in [`LOG_FILE`](crate::LOG_FILE).
+    #[serde(default)]
+    pub logged: bool,
+}
+
+impl RunRecord {
+    /// Returns the invoking user, from `SUDO_USER` and `SUDO_UID` or `PKEXEC_UID`.
+    pub fn invoking_user() -> (Option<String>, Option<u32>) {
+        let env_uid = |name| env::var(name).ok().and_then(|uid| uid.parse::<u32>().ok());
+        if let Ok(user) = env::var("SUDO_USER") {
+            return (Some(user), env_uid("SUDO_UID"));
+        }
+        match env_uid("PKEXEC_UID") {
+            Some(uid) => (user_name(uid), Some(uid)),
+            None => (None, None),
+        }
+    }
+}
+
+/// A record of why a root generation exists, stored inside it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GenerationMetadata {
+    /// The subvolid of the root this was written for. Snapshots inherit the file from their
+    /// parent, so it only describes a root with the same subvolid.
+    pub subvolid: u64,
+    /// Set if the root was created by `pop-core run`.
+    pub run: Option<RunRecord>,
+}
+
+impl GenerationMetadata {
+    /// Creates an empty record for the root with `subvolid`.
+    pub fn new(subvolid: u64) -> Self {
+        Self {
+            subvolid,
+            run: None,
+        }
+    }
+
+    /// Writes the record into `root_dir`.
+    pub fn write<P: AsRef<Path>>(&self, root_dir: P) -> io::Result<()> {
+        let path = root_dir.as_ref().join(METADATA_FILE);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_vec_pretty(self)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::write(path, data)
+    }
+
+    /// Reads the record of `root_dir`, if it has one of its own.
+    pub fn read<P: AsRef<Path>>(root_dir: P) -> io::Result<Option<Self>> {
+        let root_dir = root_dir.as_ref();
+        let data = match fs::read(root_dir.join(METADATA_FILE)) {
+            Ok(ok) => ok,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let metadata: Self = serde_json::from_slice(&data).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "failed to parse metadata of {}: {}",
+                    root_dir.display(),
+                    err
+                ),
+            )
+        })?;
+        if metadata.subvolid == btrfs::subvolume_id(root_dir)? {
+            Ok(Some(metadata))
+        } else {
+            Ok(None)
+        }
+    }
+}
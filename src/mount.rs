@@ -1,12 +1,13 @@
-use libc::{c_ulong, c_void, mount, umount2, MNT_DETACH};
+use libc::{c_void, mount, umount2, MNT_DETACH};
 use std::ffi::CString;
 use std::io::{Error, ErrorKind, Result};
+use std::os::raw::c_ulong;
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::ptr;
 
 /// Unmounts a regular partition, which may optionally be lazily-unmounted.
-fn umount<P: AsRef<Path>>(dest: P, lazy: bool) -> Result<()> {
+pub(crate) fn umount<P: AsRef<Path>>(dest: P, lazy: bool) -> Result<()> {
     unsafe {
         let mount = CString::new(dest.as_ref().as_os_str().as_bytes().to_owned());
         let mount_ptr = mount
@@ -24,7 +25,7 @@ fn umount<P: AsRef<Path>>(dest: P, lazy: bool) -> Result<()> {
 pub struct Mounts(pub Vec<Mount>);
 
 impl Mounts {
-    #[cfg_attr(rustfmt, rustfmt_skip)]
+    #[rustfmt::skip]
     pub fn unmount(&mut self, lazy: bool) -> Result<()> {
         for mount in self.0.iter_mut().rev() {
             mount.unmount(lazy)?;
@@ -104,6 +105,10 @@ impl Mount {
         }
     }
 
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
     pub fn dest(&self) -> &Path {
         &self.dest
     }
@@ -114,10 +119,7 @@ impl Mount {
             self.unmount(true)?;
             res
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                format!("mount point not mounted"),
-            ))
+            Err(Error::new(ErrorKind::Other, "mount point not mounted"))
         }
     }
 
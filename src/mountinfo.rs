@@ -0,0 +1,298 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    str,
+};
+
+use crate::btrfs;
+
+/// A mount, as described by a line of `/proc/self/mountinfo`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct MountInfo {
+    pub major: u32,
+    pub minor: u32,
+    /// The path inside the filesystem that is mounted, such as `/@root.1` for a subvolume.
+    pub root: PathBuf,
+    pub mount_point: PathBuf,
+    pub fstype: String,
+    pub source: String,
+}
+
+/// Replaces the octal escapes the kernel uses for whitespace and backslashes.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal = str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or_default();
+            if let Ok(byte) = u8::from_str_radix(octal, 8) {
+                unescaped.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+impl MountInfo {
+    /// Parses one line of a mountinfo file.
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let _mount_id = fields.next()?;
+        let _parent_id = fields.next()?;
+        let (major, minor) = fields.next()?.split_once(':')?;
+        let root = unescape(fields.next()?);
+        let mount_point = unescape(fields.next()?);
+        let _options = fields.next()?;
+        // Optional fields are ended by a single hyphen
+        for field in fields.by_ref() {
+            if field == "-" {
+                break;
+            }
+        }
+        let fstype = unescape(fields.next()?);
+        let source = unescape(fields.next()?);
+
+        Some(Self {
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+            root: PathBuf::from(root),
+            mount_point: PathBuf::from(mount_point),
+            fstype,
+            source,
+        })
+    }
+
+    /// Parses every line of a mountinfo file.
+    pub fn parse(data: &str) -> io::Result<Vec<Self>> {
+        data.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                Self::parse_line(line).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("failed to parse mountinfo line {:?}", line),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Returns every mount visible to this process.
+    pub fn all() -> io::Result<Vec<Self>> {
+        Self::parse(&fs::read_to_string("/proc/self/mountinfo")?)
+    }
+
+    /// Finds the mount at `mount_point` in `mounts`. If several are stacked there, the last one
+    /// is used, as it hides the others.
+    pub fn find<P: AsRef<Path>>(mounts: Vec<Self>, mount_point: P) -> io::Result<Self> {
+        let mount_point = mount_point.as_ref();
+        mounts
+            .into_iter()
+            .rev()
+            .find(|mount| mount.mount_point == mount_point)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no mount found at {}", mount_point.display()),
+                )
+            })
+    }
+
+    /// Returns the mount at `mount_point`, which must be the root of a mount.
+    pub fn from_mount_point<P: AsRef<Path>>(mount_point: P) -> io::Result<Self> {
+        let mount_point = fs::canonicalize(mount_point)?;
+        Self::find(Self::all()?, mount_point)
+    }
+}
+
+/// Formats 16 bytes as a UUID, without changing the byte order.
+pub fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..].concat()
+    )
+}
+
+/// Formats a GUID as stored on disk in a GPT, where the first three fields are little endian.
+pub fn format_guid(bytes: &[u8; 16]) -> String {
+    let mut uuid = *bytes;
+    uuid[..4].reverse();
+    uuid[4..6].reverse();
+    uuid[6..8].reverse();
+    format_uuid(&uuid)
+}
+
+/// Returns the UUID of the btrfs filesystem mounted at `mount_point`.
+pub fn filesystem_uuid<P: AsRef<Path>>(mount_point: P) -> io::Result<String> {
+    let mount_point = mount_point.as_ref();
+    let mount = MountInfo::from_mount_point(mount_point)?;
+    if mount.fstype != "btrfs" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is {}, not btrfs", mount_point.display(), mount.fstype),
+        ));
+    }
+    Ok(format_uuid(&btrfs::filesystem_uuid(mount_point)?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Returns the GPT partition UUID of the partition mounted at `mount_point`.
+pub fn partition_uuid<P: AsRef<Path>>(mount_point: P) -> io::Result<String> {
+    let mount = MountInfo::from_mount_point(mount_point)?;
+
+    // For a partition, sysfs has its number and the disk is the parent directory
+    let sys_dir = fs::canonicalize(format!("/sys/dev/block/{}:{}", mount.major, mount.minor))?;
+    let number: u64 = fs::read_to_string(sys_dir.join("partition"))
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{} is not a partition: {}", mount.source, err),
+            )
+        })?
+        .trim()
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let disk_dir = sys_dir.parent().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no disk found for {}", sys_dir.display()),
+        )
+    })?;
+    let block_size: u64 = fs::read_to_string(disk_dir.join("queue/logical_block_size"))?
+        .trim()
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let disk_name = disk_dir.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no disk found for {}", sys_dir.display()),
+        )
+    })?;
+
+    let mut disk = File::open(Path::new("/dev").join(disk_name))?;
+
+    // The GPT header is in the second block
+    let mut header = [0; 92];
+    disk.seek(SeekFrom::Start(block_size))?;
+    disk.read_exact(&mut header)?;
+    if &header[..8] != b"EFI PART" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} does not have a GPT", disk_name.to_string_lossy()),
+        ));
+    }
+    let entries_lba = read_u64(&header, 72);
+    let entries_count = read_u32(&header, 80);
+    let entry_size = read_u32(&header, 84);
+    if number == 0 || number > u64::from(entries_count) || entry_size < 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "partition {} not found in GPT of {}",
+                number,
+                disk_name.to_string_lossy()
+            ),
+        ));
+    }
+
+    // Each entry starts with the type GUID followed by the unique GUID
+    let mut entry = [0; 32];
+    disk.seek(SeekFrom::Start(
+        entries_lba * block_size + (number - 1) * u64::from(entry_size),
+    ))?;
+    disk.read_exact(&mut entry)?;
+    Ok(format_guid(entry[16..].try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 0:21 /@root.3 / ro,relatime shared:1 - btrfs /dev/nvme0n1p2 ro,ssd,space_cache=v2,subvolid=262,subvol=/@root.3
+23 22 0:5 / /dev rw,nosuid,relatime shared:2 - devtmpfs udev rw,size=8035536k,nr_inodes=2008884,mode=755
+24 22 0:22 / /proc rw,nosuid,nodev,noexec,relatime shared:12 - proc proc rw
+41 22 259:1 / /boot/efi rw,relatime shared:29 - vfat /dev/nvme0n1p1 rw,fmask=0077,dmask=0077
+42 22 0:21 /@home /home rw,relatime shared:31 - btrfs /dev/nvme0n1p2 rw,ssd,space_cache=v2,subvolid=257,subvol=/@home
+51 22 0:21 / /tmp/pop\\040core rw,relatime - btrfs /dev/nvme0n1p2 rw,ssd,space_cache=v2,subvolid=5,subvol=/
+";
+
+    #[test]
+    fn parse_mountinfo() {
+        let mounts = MountInfo::parse(MOUNTINFO).unwrap();
+        assert_eq!(mounts.len(), 6);
+        assert_eq!(
+            mounts[0],
+            MountInfo {
+                major: 0,
+                minor: 21,
+                root: PathBuf::from("/@root.3"),
+                mount_point: PathBuf::from("/"),
+                fstype: "btrfs".to_string(),
+                source: "/dev/nvme0n1p2".to_string(),
+            }
+        );
+        assert_eq!(mounts[3].major, 259);
+        assert_eq!(mounts[3].minor, 1);
+        assert_eq!(mounts[3].fstype, "vfat");
+        assert_eq!(mounts[5].mount_point, Path::new("/tmp/pop core"));
+    }
+
+    #[test]
+    fn parse_mountinfo_without_optional_fields() {
+        let mount = MountInfo::parse_line(
+            "51 22 0:21 / /mnt rw,relatime - btrfs /dev/sda2 rw,subvolid=5,subvol=/",
+        )
+        .unwrap();
+        assert_eq!(mount.mount_point, Path::new("/mnt"));
+        assert_eq!(mount.fstype, "btrfs");
+        assert_eq!(mount.source, "/dev/sda2");
+    }
+
+    #[test]
+    fn parse_mountinfo_invalid() {
+        assert!(MountInfo::parse_line("51 22 0:21 / /mnt rw,relatime").is_none());
+        assert!(MountInfo::parse("51 22 zero / /mnt rw - btrfs /dev/sda2 rw\n").is_err());
+    }
+
+    #[test]
+    fn find_mount_point() {
+        let stacked = format!("{}60 22 0:30 / / rw - overlay overlay rw\n", MOUNTINFO);
+        let mount = MountInfo::find(MountInfo::parse(&stacked).unwrap(), "/").unwrap();
+        assert_eq!(mount.fstype, "overlay");
+
+        let mount = MountInfo::find(MountInfo::parse(MOUNTINFO).unwrap(), "/boot/efi").unwrap();
+        assert_eq!(mount.source, "/dev/nvme0n1p1");
+
+        let err = MountInfo::find(MountInfo::parse(MOUNTINFO).unwrap(), "/var").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn format_uuids() {
+        let bytes = [
+            0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e,
+            0xc9, 0x3b,
+        ];
+        assert_eq!(format_uuid(&bytes), "28732ac1-1ff8-d211-ba4b-00a0c93ec93b");
+        assert_eq!(format_guid(&bytes), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{btrfs, load_generations, with_locked_top_dir, Generations};
+
+/// The subvolids of pinned roots, stored in `pop-core.pins` in the btrfs top level next to the
+/// update journal. Pins are not kept in a root's [`METADATA_FILE`](crate::METADATA_FILE), as
+/// roots are read-only, and making one writable to pin it would change its btrfs transid, which
+/// `diff --files` relies on. `pop-core status --json` reports them as `pinned` of each root.
+pub(crate) struct Pins {
+    path: PathBuf,
+    subvolids: BTreeSet<u64>,
+}
+
+impl Pins {
+    /// Reads the pins stored in `top_dir`, or none if there are no pins yet.
+    pub fn load(top_dir: &Path) -> io::Result<Self> {
+        let path = top_dir.join("pop-core.pins");
+        let subvolids = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("failed to parse {}: {}", path.display(), err),
+                )
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self { path, subvolids })
+    }
+
+    pub fn contains(&self, subvolid: u64) -> bool {
+        self.subvolids.contains(&subvolid)
+    }
+
+    /// Pins or unpins `subvolid`, returning false if it already was.
+    pub fn set(&mut self, subvolid: u64, pinned: bool) -> bool {
+        if pinned {
+            self.subvolids.insert(subvolid)
+        } else {
+            self.subvolids.remove(&subvolid)
+        }
+    }
+
+    /// Replaces the stored pins, making sure they are on disk before returning.
+    pub fn save(&self) -> io::Result<()> {
+        let data = serde_json::to_vec(&self.subvolids)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let partial_path = self.path.with_extension("pins.partial");
+        let mut file = File::create(&partial_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&partial_path, &self.path)?;
+        match self.path.parent() {
+            Some(parent) => File::open(parent)?.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Pins or unpins the root `target` in `generations`, returning its name.
+pub(crate) fn set_pinned(
+    generations: &Generations,
+    target: &str,
+    pinned: bool,
+) -> io::Result<String> {
+    let (name, root_dir) = generations.find(target)?;
+    let mut pins = Pins::load(generations.top_dir())?;
+    if pins.set(btrfs::subvolume_id(root_dir)?, pinned) {
+        pins.save()?;
+    }
+    Ok(name)
+}
+
+fn pin_with_top_dir(top_dir: &Path, target: &str, pinned: bool) -> io::Result<()> {
+    let generations = load_generations(top_dir)?;
+    let name = set_pinned(&generations, target, pinned)?;
+    if pinned {
+        log::info!("Pinned {}, it will not be deleted", name);
+    } else {
+        log::info!("Unpinned {}", name);
+    }
+    Ok(())
+}
+
+/// Pins a root, so that it is never deleted by garbage collection or any other command. If
+/// another pop-core is running, this waits for it if `wait` is true, and fails otherwise.
+pub fn pin(target: String, wait: bool) -> io::Result<()> {
+    with_locked_top_dir(wait, |top_dir| pin_with_top_dir(top_dir, &target, true))
+}
+
+/// Unpins a root, so that it can be deleted again. If another pop-core is running, this waits
+/// for it if `wait` is true, and fails otherwise.
+pub fn unpin(target: String, wait: bool) -> io::Result<()> {
+    with_locked_top_dir(wait, |top_dir| pin_with_top_dir(top_dir, &target, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generation_name, with_test_btrfs};
+
+    #[test]
+    fn store_pins() {
+        let dir = crate::util::create_temp_dir("pop-core-pins").unwrap();
+
+        let mut pins = Pins::load(&dir).unwrap();
+        assert!(!pins.contains(256));
+        assert!(pins.set(256, true));
+        assert!(!pins.set(256, true));
+        assert!(pins.set(300, true));
+        pins.save().unwrap();
+
+        let mut pins = Pins::load(&dir).unwrap();
+        assert!(pins.contains(256));
+        assert!(pins.contains(300));
+        assert!(pins.set(256, false));
+        assert!(!pins.set(256, false));
+        pins.save().unwrap();
+
+        let pins = Pins::load(&dir).unwrap();
+        assert!(!pins.contains(256));
+        assert!(pins.contains(300));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Needs root, btrfs-progs and losetup, so run with `sudo cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn pinning_leaves_root_unchanged() {
+        with_test_btrfs(|mount_dir| {
+            let root = mount_dir.join(generation_name(1));
+            btrfs::create_subvolume(&root)?;
+            fs::write(root.join("file"), "data")?;
+            btrfs::set_readonly(&root, true)?;
+            btrfs::set_default(&root)?;
+            btrfs::sync(&root)?;
+            let before = btrfs::subvolume_info(&root)?;
+
+            let generations = Generations::load(mount_dir, 0)?;
+            set_pinned(&generations, "1", true)?;
+            btrfs::sync(&root)?;
+            let after = btrfs::subvolume_info(&root)?;
+            assert!(after.readonly);
+            assert_eq!(after.generation, before.generation);
+            assert_eq!(after.ctransid, before.ctransid);
+            assert!(Pins::load(mount_dir)?.contains(after.id));
+
+            set_pinned(&generations, "1", false)?;
+            assert!(!Pins::load(mount_dir)?.contains(after.id));
+            Ok(())
+        })
+        .unwrap();
+    }
+}
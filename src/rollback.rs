@@ -0,0 +1,108 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    btrfs, clean_root_new, commit_root_new, load_generations, loader, parse_generation_name,
+    with_locked_top_dir, Generations,
+};
+
+/// Copies a named snapshot such as `@root.original` into a new generation and makes it the
+/// default, returning its path. Snapshots are copied so that they are never modified, and later
+/// runs can build on top of them.
+pub(crate) fn restore_snapshot(generations: &Generations, name: &str) -> io::Result<PathBuf> {
+    let default = generations.default().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "Default subvolume is not a root generation",
+        )
+    })?;
+    if !name.starts_with("@root.") || name.contains('/') || name == "@root.new" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid rollback target {:?}", name),
+        ));
+    }
+    let snapshot = generations.top_dir().join(name);
+    if !snapshot.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} not found", name),
+        ));
+    }
+
+    let root_new = clean_root_new(generations.top_dir(), generations.booted_subvolid())?;
+
+    log::info!("Creating read-only snapshot of {} named @root.new", name);
+    btrfs::snapshot(&snapshot, &root_new, true)?;
+
+    commit_root_new(generations, &root_new, default)
+}
+
+fn rollback_with_top_dir(top_dir: &Path, target: Option<&str>) -> io::Result<()> {
+    let generations = load_generations(top_dir)?;
+    let default = generations.default().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "Default subvolume is not a root generation",
+        )
+    })?;
+
+    let number = match target {
+        None | Some("@root.old") => match generations.before(default.number()) {
+            Some(generation) => Some(generation.number()),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No generation older than {}", default.name()),
+                ))
+            }
+        },
+        Some(name) => name
+            .parse::<u64>()
+            .ok()
+            .or_else(|| parse_generation_name(name)),
+    };
+
+    match number {
+        Some(number) => {
+            let generation = generations.get(number).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Generation {} not found", number),
+                )
+            })?;
+            if generation.number() == default.number() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} is already the default", generation.name()),
+                ));
+            }
+
+            log::info!("Setting {} as default subvolume", generation.name());
+            btrfs::set_default(generation.path())?;
+
+            log::debug!("Pointing old loader entry at {}", default.name());
+            loader::set_entry_subvol("Pop_OS-old", &default.name())?;
+        }
+        None => {
+            let root = restore_snapshot(&generations, target.unwrap_or_default())?;
+            log::info!("Set {} as default subvolume", root.display());
+        }
+    }
+
+    loader::set_default_entry("Pop_OS-current")?;
+
+    log::info!("Rollback complete, reboot to use it");
+    Ok(())
+}
+
+/// Makes an older generation, or a named snapshot such as `@root.original`, the default root.
+/// Without a target, the generation before the current default is used. If another pop-core is
+/// running, this waits for it if `wait` is true, and fails otherwise.
+pub fn rollback(target: Option<String>, wait: bool) -> io::Result<()> {
+    with_locked_top_dir(wait, |top_dir| {
+        rollback_with_top_dir(top_dir, target.as_deref())
+    })
+}
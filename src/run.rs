@@ -1,230 +1,755 @@
+use serde::{Deserialize, Serialize};
 use std::{
-    fs, io,
-    path::Path,
-    process::{Command, Stdio},
+    error, fmt, fs, io,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
     str,
 };
 
 use crate::{
-    util::{check_output, check_status},
-    Mount,
+    btrfs, collect_garbage, delete_root, filesystem_uuid, garbage, generation_name, loader,
+    logged_command, migrate_legacy, store_log,
+    util::{
+        create_temp_dir, format_size, free_space, now, rename_noreplace, status_ignoring_interrupts,
+    },
+    Config, Generation, GenerationMetadata, Generations, HookStage, Hooks, Journal, JournalEntry,
+    Lock, Mount, RetentionPolicy, RunRecord, SpacePolicy, CONFIG_FILE, HOOKS_DIR, LOCK_FILE,
 };
 
-fn btrfs_subvolid<P: AsRef<Path>>(path: P) -> io::Result<String> {
-    let output = Command::new("btrfs")
-        .arg("--quiet")
-        .arg("inspect-internal")
-        .arg("rootid")
-        .arg(path.as_ref())
-        .stdout(Stdio::piped())
-        .spawn()?
-        .wait_with_output()
-        .and_then(check_output)?;
-
-    str::from_utf8(&output.stdout)
-        .map(|x| x.trim().to_string())
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
-}
-
-//TODO: could use atomic swaps (renameat2?)
-fn run_with_top_dir(top_dir: &Path, command: &String, args: &Vec<String>) -> io::Result<()> {
-    log::debug!("Getting root subvolid");
-    let root_subvolid = btrfs_subvolid("/")?;
-
-    log::debug!("Getting hostname");
-    let hostname = fs::read_to_string("/etc/hostname")?.trim().to_string();
-
-    //TODO: use generation
-    let root = top_dir.join("@root");
+/// Returns the path of `@root.new`, deleting any copy left behind by an earlier run.
+pub(crate) fn clean_root_new(top_dir: &Path, root_subvolid: u64) -> io::Result<PathBuf> {
     let root_new = top_dir.join("@root.new");
-    let root_old = top_dir.join("@root.old");
-
     if root_new.exists() {
-        if btrfs_subvolid(&root_new)? == root_subvolid {
+        if btrfs::subvolume_id(&root_new)? == root_subvolid {
             return Err(io::Error::new(
                 io::ErrorKind::AlreadyExists,
                 "Booted root somehow at @root.new",
             ));
         } else {
             log::debug!("Deleting @root.new");
-            Command::new("btrfs")
-                .arg("--quiet")
-                .arg("subvolume")
-                .arg("delete")
-                .arg(&root_new)
-                .status()
-                .and_then(check_status)?;
+            delete_root(&root_new)?;
         }
     }
+    Ok(root_new)
+}
 
-    log::debug!("Creating writable snapshot of @root named @root.new");
-    Command::new("btrfs")
-        .arg("--quiet")
-        .arg("subvolume")
-        .arg("snapshot")
-        .arg(&root)
-        .arg(&root_new)
-        .status()
-        .and_then(check_status)?;
-
-    //TODO: capture result and cleanup @root.new?
-    log::debug!("Running command in container");
-    Command::new("systemd-nspawn")
+/// Atomically moves `root_new` to `root`, which must not exist yet.
+fn promote_root_new(root_new: &Path, root: &Path) -> io::Result<()> {
+    log::debug!("Moving @root.new to {}", root.display());
+    rename_noreplace(root_new, root).map_err(|err| {
+        if err.kind() == io::ErrorKind::AlreadyExists {
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", root.display()),
+            )
+        } else {
+            err
+        }
+    })
+}
+
+/// Promotes a read-only `@root.new` to the next generation and makes it the default subvolume.
+/// The old loader entry is pointed at `parent`, the generation that was default before.
+pub(crate) fn commit_root_new(
+    generations: &Generations,
+    root_new: &Path,
+    parent: &Generation,
+) -> io::Result<PathBuf> {
+    let root = generations.path(generations.next_number());
+    promote_root_new(root_new, &root)?;
+
+    log::debug!("Setting {} as default subvolume", root.display());
+    btrfs::set_default(&root)?;
+
+    log::debug!("Pointing old loader entry at {}", parent.name());
+    loader::set_entry_subvol("Pop_OS-old", &parent.name())?;
+
+    Ok(root)
+}
+
+/// What was done with an update that was interrupted.
+#[derive(Debug)]
+pub(crate) enum Recovery {
+    /// The update had not finished its command or was discarded, so `@root.new` was deleted.
+    RolledBack,
+    /// The update had finished its command, so it was committed. Contains the numbers of the
+    /// parent generation and the new one.
+    RolledForward(u64, u64),
+}
+
+/// Returns true if the interrupted update recorded in `entry` should be committed, given whether
+/// `@root.new` and the generation it is promoted to exist. Updates that had not finished their
+/// command or were discarded are rolled back, as is one with nothing left to commit.
+fn rolls_forward(entry: &JournalEntry, root_new_exists: bool, root_exists: bool) -> bool {
+    if entry.discarded {
+        return false;
+    }
+    match entry.completed {
+        None | Some(UpdateStep::Snapshot) => false,
+        Some(_) => root_new_exists || root_exists,
+    }
+}
+
+/// Finishes or undoes an update that was interrupted, based on the journal in the top level.
+pub(crate) fn recover(generations: &Generations) -> io::Result<Option<Recovery>> {
+    let journal = Journal::new(generations.top_dir());
+    let entry = match journal.read()? {
+        Some(some) => some,
+        None => return Ok(None),
+    };
+    log::debug!("Found journal {:?}", entry);
+
+    let root_new_exists = generations.top_dir().join("@root.new").exists();
+    let root_exists = generations.path(entry.root).exists();
+    if rolls_forward(&entry, root_new_exists, root_exists) {
+        let mut update = Update::<fn(&Path) -> io::Result<bool>>::resume(generations, &entry)?;
+        while let Some(step) = update.next_step() {
+            log::trace!("Update step {:?}", step);
+            update.step()?;
+        }
+        Ok(Some(Recovery::RolledForward(entry.parent, entry.root)))
+    } else {
+        clean_root_new(generations.top_dir(), generations.booted_subvolid())?;
+        journal.remove()?;
+        Ok(Some(Recovery::RolledBack))
+    }
+}
+
+/// Loads the root generations in `top_dir`, migrating an older layout and recovering an
+/// interrupted update first.
+pub(crate) fn load_generations(top_dir: &Path) -> io::Result<Generations> {
+    log::debug!("Getting root subvolid");
+    let root_subvolid = btrfs::subvolume_id("/")?;
+
+    migrate_legacy(top_dir)?;
+
+    let generations = Generations::load(top_dir, root_subvolid)?;
+    match recover(&generations)? {
+        Some(Recovery::RolledBack) => {
+            log::warn!("Rolled back interrupted update by deleting @root.new");
+        }
+        Some(Recovery::RolledForward(parent, root)) => {
+            let generations = Generations::load(top_dir, root_subvolid)?;
+            let (parent, root) = match (generations.get(parent), generations.get(root)) {
+                (Some(parent), Some(root)) => (parent, root),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "{} or {} not found after recovery",
+                            generation_name(parent),
+                            generation_name(root)
+                        ),
+                    ))
+                }
+            };
+            log::warn!(
+                "Rolled forward interrupted update, set {} as default subvolume",
+                root.path().display()
+            );
+
+            // Finished like any other commit, so hooks and garbage collection are not skipped
+            let config = Config::load(CONFIG_FILE)?;
+            let hooks = Hooks::new(HOOKS_DIR);
+            let hostname = fs::read_to_string("/etc/hostname")?.trim().to_string();
+            finish_commit(
+                &generations,
+                parent,
+                root.path(),
+                &root.name(),
+                &config,
+                &hooks,
+                &hostname,
+            )?;
+        }
+        None => return Ok(generations),
+    }
+
+    Generations::load(top_dir, root_subvolid)
+}
+
+/// The steps taken to replace the default root, in order. Promotion never replaces an existing
+/// name and the default subvolume only changes in `SetDefault`, so the filesystem is bootable
+/// after every step.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) enum UpdateStep {
+    Snapshot,
+    Command,
+    ReadOnly,
+    Promote,
+    SetDefault,
+}
+
+impl UpdateStep {
+    fn next(self) -> Option<Self> {
+        match self {
+            Self::Snapshot => Some(Self::Command),
+            Self::Command => Some(Self::ReadOnly),
+            Self::ReadOnly => Some(Self::Promote),
+            Self::Promote => Some(Self::SetDefault),
+            Self::SetDefault => None,
+        }
+    }
+}
+
+/// Creates a new generation from the default one by running `command` on a writable snapshot.
+/// The command returns false if the snapshot should be discarded instead of committed.
+pub(crate) struct Update<'a, F> {
+    generations: &'a Generations,
+    parent: &'a Generation,
+    root_new: PathBuf,
+    root: PathBuf,
+    command: Option<F>,
+    step: Option<UpdateStep>,
+    journal: Journal,
+    entry: JournalEntry,
+}
+
+impl<'a, F: FnOnce(&Path) -> io::Result<bool>> Update<'a, F> {
+    pub fn new(generations: &'a Generations, command: F) -> io::Result<Self> {
+        let parent = generations.default().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "Default subvolume is not a root generation",
+            )
+        })?;
+        let number = generations.next_number();
+        Ok(Self {
+            generations,
+            parent,
+            root_new: generations.top_dir().join("@root.new"),
+            root: generations.path(number),
+            command: Some(command),
+            step: Some(UpdateStep::Snapshot),
+            journal: Journal::new(generations.top_dir()),
+            entry: JournalEntry {
+                parent: parent.number(),
+                root: number,
+                completed: None,
+                discarded: false,
+            },
+        })
+    }
+
+    /// Continues the update recorded in `entry` after its last completed step.
+    pub fn resume(generations: &'a Generations, entry: &JournalEntry) -> io::Result<Self> {
+        let parent = generations.get(entry.parent).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Journal parent {} not found", generation_name(entry.parent)),
+            )
+        })?;
+        Ok(Self {
+            generations,
+            parent,
+            root_new: generations.top_dir().join("@root.new"),
+            root: generations.path(entry.root),
+            command: None,
+            step: match entry.completed {
+                Some(completed) => completed.next(),
+                None => Some(UpdateStep::Snapshot),
+            },
+            journal: Journal::new(generations.top_dir()),
+            entry: entry.clone(),
+        })
+    }
+
+    /// The path of the generation that will be created.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The step that will run next, or `None` if all steps are complete.
+    pub fn next_step(&self) -> Option<UpdateStep> {
+        self.step
+    }
+
+    /// Returns true if the command chose to discard the snapshot.
+    pub fn discarded(&self) -> bool {
+        self.entry.discarded
+    }
+
+    /// Deletes `@root.new` and the journal, abandoning the update. Only valid before promotion.
+    pub fn abort(&mut self) -> io::Result<()> {
+        // Recorded first, so recovery does not commit what was abandoned
+        self.entry.discarded = true;
+        self.journal.write(&self.entry)?;
+        clean_root_new(
+            self.generations.top_dir(),
+            self.generations.booted_subvolid(),
+        )?;
+        self.journal.remove()?;
+        self.step = None;
+        Ok(())
+    }
+
+    /// Runs the next step, returning `false` if all steps are complete.
+    pub fn step(&mut self) -> io::Result<bool> {
+        let step = match self.step {
+            Some(some) => some,
+            None => return Ok(false),
+        };
+
+        if self.entry.completed.is_none() {
+            self.journal.write(&self.entry)?;
+        }
+
+        match step {
+            UpdateStep::Snapshot => {
+                clean_root_new(
+                    self.generations.top_dir(),
+                    self.generations.booted_subvolid(),
+                )?;
+
+                log::debug!(
+                    "Creating writable snapshot of {} named @root.new",
+                    self.parent.name()
+                );
+                btrfs::snapshot(self.parent.path(), &self.root_new, false)?;
+            }
+            UpdateStep::Command => {
+                if let Some(command) = self.command.take() {
+                    if !command(&self.root_new)? {
+                        self.abort()?;
+                        return Ok(true);
+                    }
+                }
+            }
+            UpdateStep::ReadOnly => {
+                log::debug!("Setting @root.new as read-only");
+                btrfs::set_readonly(&self.root_new, true)?;
+            }
+            UpdateStep::Promote => {
+                // May already be done if interrupted before the journal was written
+                if self.root_new.exists() || !self.root.exists() {
+                    promote_root_new(&self.root_new, &self.root)?;
+                }
+            }
+            UpdateStep::SetDefault => {
+                log::debug!("Setting {} as default subvolume", self.root.display());
+                btrfs::set_default(&self.root)?;
+            }
+        }
+
+        self.step = step.next();
+        if self.step.is_some() {
+            self.entry.completed = Some(step);
+            self.journal.write(&self.entry)?;
+        } else {
+            self.journal.remove()?;
+        }
+        Ok(true)
+    }
+}
+
+/// The command run in the container did not exit successfully.
+#[derive(Debug)]
+pub struct CommandFailed(pub ExitStatus);
+
+impl CommandFailed {
+    /// The exit code to pass on, using the shell convention of 128 plus the signal number if the
+    /// command was killed by a signal.
+    pub fn code(&self) -> i32 {
+        match (self.0.code(), self.0.signal()) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        }
+    }
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "command failed with {}", self.0)
+    }
+}
+
+impl error::Error for CommandFailed {}
+
+/// Options for [`run`].
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    /// Keep `@root.new` for debugging if the command fails, instead of deleting it.
+    pub keep_failed: bool,
+    /// Wait for another running pop-core to finish, instead of failing.
+    pub wait: bool,
+    /// Commit a new generation even if the command did not change the root. Changes made by
+    /// pre and post-command hooks are not counted.
+    pub force_commit: bool,
+    /// Only warn if there is less free space than [`SpacePolicy`] requires, instead of failing.
+    pub ignore_low_space: bool,
+}
+
+/// Checks that the filesystem of `generations` has the free space required by `policy`, as an
+/// update that runs out of space fails halfway through. If it does not, this fails or, if
+/// `ignore` is true, warns, suggesting old roots to delete.
+fn check_free_space(
+    generations: &Generations,
+    policy: &SpacePolicy,
+    ignore: bool,
+) -> io::Result<()> {
+    let required = policy.min_free_mib.saturating_mul(1024 * 1024);
+    let free = free_space(generations.top_dir())?;
+    log::debug!(
+        "{} free, {} required",
+        format_size(free),
+        format_size(required)
+    );
+    if free >= required {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "only {} is free on the root filesystem, and updates need {}",
+        format_size(free),
+        format_size(required)
+    );
+
+    // Suggest what garbage collection keeping only the protected and latest roots would delete.
+    // This is only a hint, so failing to work it out does not stop the update.
+    let gc_policy = RetentionPolicy {
+        keep_last: 1,
+        keep_days: 0,
+        ..RetentionPolicy::default()
+    };
+    let garbage = garbage(generations, &gc_policy).unwrap_or_else(|err| {
+        log::warn!("Failed to find old roots to delete: {}", err);
+        Vec::new()
+    });
+    let mut names = Vec::new();
+    let mut freed = Some(0);
+    for number in garbage {
+        let generation = match generations.get(number) {
+            Some(some) => some,
+            None => continue,
+        };
+        let exclusive = btrfs::qgroup_exclusive(generations.top_dir(), generation.subvolid())
+            .unwrap_or_else(|err| {
+                log::warn!(
+                    "Failed to get the space used by {}: {}",
+                    generation.name(),
+                    err
+                );
+                None
+            });
+        freed = match (freed, exclusive) {
+            (Some(freed), Some(exclusive)) => Some(freed + exclusive),
+            _ => None,
+        };
+        let old = generations
+            .default()
+            .and_then(|default| generations.before(default.number()))
+            .map_or(false, |old| old.number() == number);
+        names.push(if old {
+            format!("{} (@root.old)", generation.name())
+        } else {
+            generation.name()
+        });
+    }
+    if !names.is_empty() {
+        message.push_str(&format!(
+            ". Deleting {} would free {}, with: pop-core gc --keep-last 1 --keep-days 0",
+            names.join(", "),
+            freed.map_or("some space".to_string(), format_size)
+        ));
+    }
+
+    if ignore {
+        log::warn!("{}", message);
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{}. Use --ignore-low-space to update anyway", message),
+        ))
+    }
+}
+
+/// Returns the btrfs generation of the subvolume at `path`, which changes whenever anything in
+/// it is written.
+fn current_generation(path: &Path) -> io::Result<u64> {
+    btrfs::sync(path)?;
+    Ok(btrfs::subvolume_info(path)?.generation)
+}
+
+/// Returns true if `@root.new` should be committed, given its btrfs generation `before` and
+/// `after` the command ran. A command that changed nothing is only committed if `force_commit`.
+fn should_commit(before: u64, after: u64, force_commit: bool) -> bool {
+    after != before || force_commit
+}
+
+/// Returns a `systemd-nspawn` command for a container of `root_dir` sharing `/home` and `/var`
+/// with the host, without the program to run.
+pub(crate) fn container_command(root_dir: &Path, hostname: &str) -> Command {
+    let mut nspawn = Command::new("systemd-nspawn");
+    nspawn
         .arg("--bind-ro=/home")
         //TODO: should more of /run be bind mounted?
         .arg("--bind-ro=/run/systemd/resolve/stub-resolv.conf")
         //TODO: should /var be snapshotted or readonly?
         .arg("--bind=/var")
-        .arg(&format!("--directory={}", root_new.display()))
+        .arg(format!("--directory={}", root_dir.display()))
         .arg("--link-journal=no")
-        .arg(&format!("--machine={}", &hostname))
+        .arg(format!("--machine={}", hostname))
         .arg("--quiet")
         .arg("--resolv-conf=off")
-        .arg("--timezone=off")
-        .arg("--")
-        .arg(command)
-        .args(args)
-        .status()
-        .and_then(check_status)?;
-
-    log::debug!("Setting @root.new as read-only");
-    Command::new("btrfs")
-        .arg("--quiet")
-        .arg("property")
-        .arg("set")
-        .arg("-t")
-        .arg("subvol")
-        .arg(&root_new)
-        .arg("ro")
-        .arg("true")
-        .status()
-        .and_then(check_status)?;
-
-    log::debug!("Setting / as default subvolume");
-    Command::new("btrfs")
-        .arg("--quiet")
-        .arg("subvolume")
-        .arg("set-default")
-        .arg("/")
-        .status()
-        .and_then(check_status)?;
-
-    log::debug!("Saving booted root as @root.old");
-    {
-        if root_old.exists() {
-            if btrfs_subvolid(&root_old)? == root_subvolid {
-                log::debug!("Booted root already saved as @root.old");
-            } else {
-                log::debug!("Deleting @root.old");
-                Command::new("btrfs")
-                    .arg("--quiet")
-                    .arg("subvolume")
-                    .arg("delete")
-                    .arg(&root_old)
-                    .status()
-                    .and_then(check_status)?;
+        .arg("--timezone=off");
+    nspawn
+}
+
+/// Returns the environment of hooks run on `root`, which is created from `parent` and will be
+/// named `root_name` once committed. Paths are on the host, under the btrfs top level.
+fn hook_env(
+    generations: &Generations,
+    parent: &Generation,
+    root: &Path,
+    root_name: &str,
+) -> io::Result<Vec<(&'static str, String)>> {
+    Ok(vec![
+        (
+            "POP_CORE_TOP_DIR",
+            generations.top_dir().display().to_string(),
+        ),
+        ("POP_CORE_ROOT", root.display().to_string()),
+        ("POP_CORE_ROOT_NAME", root_name.to_string()),
+        (
+            "POP_CORE_ROOT_SUBVOLID",
+            btrfs::subvolume_id(root)?.to_string(),
+        ),
+        (
+            "POP_CORE_BOOTED_SUBVOLID",
+            generations.booted_subvolid().to_string(),
+        ),
+        ("POP_CORE_PARENT", parent.path().display().to_string()),
+        ("POP_CORE_PARENT_NAME", parent.name()),
+        ("POP_CORE_PARENT_SUBVOLID", parent.subvolid().to_string()),
+    ])
+}
+
+/// Finishes the commit of `root`, now the default generation and created from `parent`, by
+/// pointing the old loader entry at `parent`, running post-commit hooks and deleting old roots
+/// if enabled. Post-commit hooks cannot abort the update, so their failures are only warned about.
+fn finish_commit(
+    generations: &Generations,
+    parent: &Generation,
+    root: &Path,
+    root_name: &str,
+    config: &Config,
+    hooks: &Hooks,
+    hostname: &str,
+) -> io::Result<()> {
+    log::debug!("Pointing old loader entry at {}", parent.name());
+    loader::set_entry_subvol("Pop_OS-old", &parent.name())?;
+
+    // The update is already committed, so failing hooks or clean up are not errors
+    let res = hook_env(generations, parent, root, root_name).and_then(|env| {
+        hooks.run(HookStage::PostCommit, &env, || {
+            let mut nspawn = container_command(root, hostname);
+            nspawn.arg("--read-only");
+            Ok(nspawn)
+        })
+    });
+    if let Err(err) = res {
+        log::warn!("Failed to run post-commit hooks: {}", err);
+    }
+
+    if config.gc.auto {
+        let res = Generations::load(generations.top_dir(), generations.booted_subvolid())
+            .and_then(|generations| collect_garbage(&generations, &config.gc));
+        match res {
+            Ok(report) => {
+                if !report.deleted.is_empty() {
+                    log::info!("{}", report.to_string().trim_end());
+                }
             }
+            Err(err) => log::warn!("Failed to delete old roots: {}", err),
         }
+    }
 
-        if !root_old.exists() {
-            if btrfs_subvolid(&root)? == root_subvolid {
-                log::debug!("Moving @root to @root.old");
-                fs::rename(&root, &root_old)?;
-            } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Booted root not found at @root",
-                ));
+    Ok(())
+}
+
+fn run_with_top_dir(
+    top_dir: &Path,
+    command: &String,
+    args: &Vec<String>,
+    options: &RunOptions,
+) -> io::Result<()> {
+    log::debug!("Getting hostname");
+    let hostname = fs::read_to_string("/etc/hostname")?.trim().to_string();
+
+    let config = Config::load(CONFIG_FILE)?;
+    let hooks = Hooks::new(HOOKS_DIR);
+    let generations = load_generations(top_dir)?;
+    check_free_space(&generations, &config.space, options.ignore_low_space)?;
+
+    let root_name = generation_name(generations.next_number());
+    let parent = generations.default().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "Default subvolume is not a root generation",
+        )
+    })?;
+    let mut update = Update::new(&generations, |root_new: &Path| {
+        let env = hook_env(&generations, parent, root_new, &root_name)?;
+        hooks.run(HookStage::Pre, &env, || {
+            Ok(container_command(root_new, &hostname))
+        })?;
+
+        // Only changes made by the command count, as hooks may write on every update
+        let before = current_generation(root_new)?;
+
+        log::debug!("Running command in container");
+        let started = now();
+        let log_path = top_dir.join("pop-core-command.log");
+        let mut nspawn = container_command(root_new, &hostname);
+        nspawn.arg("--").arg(command).args(args);
+        let status = status_ignoring_interrupts(&mut logged_command(&nspawn, &log_path))?;
+        let finished = now();
+
+        let mut after = before;
+        if status.success() {
+            after = current_generation(root_new)?;
+            hooks.run(HookStage::PostCommand, &env, || {
+                Ok(container_command(root_new, &hostname))
+            })?;
+        }
+
+        // Also written for failed commands, in case the snapshot is kept for debugging
+        log::debug!("Storing command log in @root.new");
+        let logged = match store_log(&log_path, root_new) {
+            Ok(()) => true,
+            Err(err) => {
+                log::warn!("Failed to store command log: {}", err);
+                false
             }
+        };
+
+        let (user, uid) = RunRecord::invoking_user();
+        log::debug!("Writing metadata to @root.new");
+        GenerationMetadata {
+            run: Some(RunRecord {
+                command: command.clone(),
+                args: args.clone(),
+                user,
+                uid,
+                started,
+                finished,
+                parent: parent.name(),
+                parent_subvolid: parent.subvolid(),
+                exit_code: if status.success() {
+                    0
+                } else {
+                    CommandFailed(status).code()
+                },
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                logged,
+            }),
+            ..GenerationMetadata::new(btrfs::subvolume_id(root_new)?)
+        }
+        .write(root_new)?;
+
+        if !status.success() {
+            return Err(io::Error::new(io::ErrorKind::Other, CommandFailed(status)));
         }
-    }
 
-    log::debug!("Saving @root.new as @root");
-    {
-        if root.exists() {
-            if btrfs_subvolid(&root)? == root_subvolid {
-                return Err(io::Error::new(
-                    io::ErrorKind::AlreadyExists,
-                    "Booted root still at @root",
-                ));
+        let commit = should_commit(before, after, options.force_commit);
+        if after == before {
+            if commit {
+                log::info!("Command made no changes, committing anyway as requested");
             } else {
-                log::debug!("Deleting @root");
-                Command::new("btrfs")
-                    .arg("--quiet")
-                    .arg("subvolume")
-                    .arg("delete")
-                    .arg(&root)
-                    .status()
-                    .and_then(check_status)?;
+                log::info!(
+                    "Command made no changes, deleting @root.new instead of committing it (use --force-commit to commit anyway)"
+                );
+            }
+        }
+        Ok(commit)
+    })?;
+    while let Some(step) = update.next_step() {
+        log::trace!("Update step {:?}", step);
+        if let Err(err) = update.step() {
+            if step == UpdateStep::Command {
+                if options.keep_failed {
+                    // Only a hint, so failing to find the device must not hide why the command
+                    // failed
+                    match root_device() {
+                        Ok(device) => log::warn!(
+                            "Kept failed snapshot for debugging, mount it with: mount -o subvol=@root.new {} /mnt",
+                            device.display()
+                        ),
+                        Err(device_err) => log::warn!(
+                            "Kept failed snapshot @root.new for debugging, failed to find the root device to mount it from: {}",
+                            device_err
+                        ),
+                    }
+                    log::warn!("It will be deleted by the next update");
+                } else {
+                    log::info!("Deleting failed snapshot @root.new");
+                    update.abort()?;
+                }
             }
+            return Err(err);
         }
+    }
 
-        log::debug!("Moving @root.new to @root");
-        fs::rename(&root_new, &root)?;
+    if update.discarded() {
+        log::info!("{} is still the default subvolume", parent.name());
+        return Ok(());
     }
 
-    log::debug!("Setting @root as default subvolume");
-    Command::new("btrfs")
-        .arg("--quiet")
-        .arg("subvolume")
-        .arg("set-default")
-        .arg(&root)
-        .status()
-        .and_then(check_status)?;
+    log::info!("Set {} as default subvolume", update.root().display());
+    finish_commit(
+        &generations,
+        parent,
+        update.root(),
+        &root_name,
+        &config,
+        &hooks,
+        &hostname,
+    )
+}
 
-    Ok(())
+/// Returns the path of the device holding the root filesystem.
+fn root_device() -> io::Result<PathBuf> {
+    log::debug!("Getting root UUID");
+    let root_uuid = filesystem_uuid("/")?;
+    Ok(Path::new("/dev/disk/by-uuid").join(root_uuid))
 }
 
-pub fn run(command: String, args: Vec<String>) -> io::Result<()> {
+fn require_root() -> io::Result<()> {
     if unsafe { libc::geteuid() } != 0 {
         return Err(io::Error::new(
             io::ErrorKind::PermissionDenied,
             "must be run as root",
         ));
     }
+    Ok(())
+}
 
-    //TODO: get root uuid without an external command
-    log::debug!("Getting root UUID");
-    let root_uuid = {
-        let output = Command::new("findmnt")
-            .arg("--noheadings")
-            .arg("--output")
-            .arg("UUID")
-            .arg("--mountpoint")
-            .arg("/")
-            .stdout(Stdio::piped())
-            .spawn()?
-            .wait_with_output()
-            .and_then(check_output)?;
-
-        str::from_utf8(&output.stdout)
-            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
-            .trim()
-            .to_string()
-    };
-
-    let top_dir = Path::new("/tmp/pop-core-change");
-    if top_dir.exists() {
-        return Err(io::Error::new(
-            io::ErrorKind::AlreadyExists,
-            "/tmp/pop-core-change already exists, is pop-core already running?",
-        ));
-    }
+fn mount_top_dir<T, F: FnOnce(&Path) -> io::Result<T>>(f: F) -> io::Result<T> {
+    let root_device = root_device()?;
 
-    // This atomically ensures only one pop-core is doing changes at a time
     log::debug!("Creating temporary directory");
-    fs::create_dir(&top_dir)?;
+    let top_dir = create_temp_dir("pop-core")?;
 
+    // Access times are not updated, so that reading files in a root does not change it
     log::debug!("Mounting btrfs top level");
-    let mut mount = Mount::new(
-        &Path::new("/dev/disk/by-uuid").join(root_uuid),
+    let mut mount = match Mount::new(
+        &root_device,
         &top_dir,
         "btrfs",
-        0,
+        libc::MS_NOATIME,
         Some("subvol=/"),
-    )?;
+    ) {
+        Ok(ok) => ok,
+        Err(err) => {
+            fs::remove_dir(&top_dir)?;
+            return Err(err);
+        }
+    };
 
-    let res = run_with_top_dir(&top_dir, &command, &args);
+    let res = f(&top_dir);
 
     log::debug!("Unmounting btrfs top level");
     match mount.unmount(false) {
@@ -239,3 +764,187 @@ pub fn run(command: String, args: Vec<String>) -> io::Result<()> {
 
     res
 }
+
+/// Mounts the btrfs top level of the root filesystem on a private temporary directory and calls
+/// `f` with its path, unmounting it afterwards. This does not prevent concurrent changes, so it
+/// is only for reading.
+pub(crate) fn with_top_dir<T, F: FnOnce(&Path) -> io::Result<T>>(f: F) -> io::Result<T> {
+    require_root()?;
+    mount_top_dir(f)
+}
+
+/// Like [`with_top_dir`], while holding [`LOCK_FILE`] so only one pop-core does changes at a
+/// time. If it is held, this waits for it if `wait` is true, and fails otherwise.
+pub(crate) fn with_locked_top_dir<T, F: FnOnce(&Path) -> io::Result<T>>(
+    wait: bool,
+    f: F,
+) -> io::Result<T> {
+    require_root()?;
+    let _lock = Lock::acquire(LOCK_FILE, wait)?;
+    mount_top_dir(f)
+}
+
+/// Runs `command` in a snapshot of the default root, and makes the snapshot the new default if
+/// it succeeds. A failed command is returned as a [`CommandFailed`] error.
+pub fn run(command: String, args: Vec<String>, options: &RunOptions) -> io::Result<()> {
+    with_locked_top_dir(options.wait, |top_dir| {
+        run_with_top_dir(top_dir, &command, &args, options)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generation_name, with_test_btrfs};
+
+    /// Checks that the default subvolume is a complete, read-only generation.
+    fn assert_bootable(top_dir: &Path, stopped: Option<UpdateStep>) {
+        let generations = Generations::load(top_dir, 0).unwrap();
+        let default = generations
+            .default()
+            .unwrap_or_else(|| panic!("no default generation when stopped at {:?}", stopped));
+        assert!(
+            btrfs::is_readonly(default.path()).unwrap(),
+            "{} is writable when stopped at {:?}",
+            default.name(),
+            stopped
+        );
+        assert!(
+            default.path().join("etc/os-release").exists(),
+            "{} is incomplete when stopped at {:?}",
+            default.name(),
+            stopped
+        );
+    }
+
+    fn journal_entry(completed: Option<UpdateStep>, discarded: bool) -> JournalEntry {
+        JournalEntry {
+            parent: 1,
+            root: 2,
+            completed,
+            discarded,
+        }
+    }
+
+    #[test]
+    fn commit_changed_root() {
+        assert!(should_commit(10, 11, false));
+        assert!(should_commit(10, 11, true));
+    }
+
+    #[test]
+    fn discard_unchanged_root() {
+        assert!(!should_commit(10, 10, false));
+        assert!(should_commit(10, 10, true));
+    }
+
+    #[test]
+    fn recover_unfinished_command() {
+        for completed in &[None, Some(UpdateStep::Snapshot)] {
+            assert!(!rolls_forward(
+                &journal_entry(*completed, false),
+                true,
+                false
+            ));
+        }
+        for completed in &[
+            UpdateStep::Command,
+            UpdateStep::ReadOnly,
+            UpdateStep::Promote,
+            UpdateStep::SetDefault,
+        ] {
+            let entry = journal_entry(Some(*completed), false);
+            assert!(rolls_forward(&entry, true, false), "{:?}", completed);
+            assert!(rolls_forward(&entry, false, true), "{:?}", completed);
+        }
+    }
+
+    #[test]
+    fn recover_discarded_update() {
+        // A command that made no changes is discarded after it completed
+        let entry = journal_entry(Some(UpdateStep::Command), true);
+        assert!(!rolls_forward(&entry, true, false));
+        assert!(!rolls_forward(&entry, false, false));
+    }
+
+    #[test]
+    fn recover_missing_root_new() {
+        // Nothing is left to commit, so resuming would fail on every later command
+        for completed in &[
+            UpdateStep::Command,
+            UpdateStep::ReadOnly,
+            UpdateStep::Promote,
+        ] {
+            let entry = journal_entry(Some(*completed), false);
+            assert!(!rolls_forward(&entry, false, false), "{:?}", completed);
+        }
+    }
+
+    #[test]
+    fn journal_without_discarded() {
+        let entry: JournalEntry =
+            serde_json::from_str(r#"{"parent":1,"root":2,"completed":"Command"}"#).unwrap();
+        assert_eq!(entry.completed, Some(UpdateStep::Command));
+        assert!(!entry.discarded);
+    }
+
+    // Needs root, btrfs-progs and losetup, so run with `sudo cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn interrupted_update_is_bootable() {
+        with_test_btrfs(|mount_dir| {
+            let root = mount_dir.join(generation_name(1));
+            btrfs::create_subvolume(&root)?;
+            fs::create_dir(root.join("etc"))?;
+            fs::write(root.join("etc/os-release"), "NAME=test\n")?;
+            btrfs::set_readonly(&root, true)?;
+            btrfs::set_default(&root)?;
+            assert_bootable(mount_dir, None);
+
+            // Interrupt an update after each step, leaving whatever it did behind for
+            // the next update to recover from
+            let steps = [
+                UpdateStep::Snapshot,
+                UpdateStep::Command,
+                UpdateStep::ReadOnly,
+                UpdateStep::Promote,
+                UpdateStep::SetDefault,
+            ];
+            for count in 0..=steps.len() {
+                let generations = Generations::load(mount_dir, 0)?;
+                let recovery = recover(&generations)?;
+                match count {
+                    0 | 1 => assert!(recovery.is_none(), "{:?}", recovery),
+                    2 => assert!(matches!(recovery, Some(Recovery::RolledBack))),
+                    _ => assert!(matches!(recovery, Some(Recovery::RolledForward(..)))),
+                }
+                assert_bootable(mount_dir, None);
+
+                let generations = Generations::load(mount_dir, 0)?;
+                let mut update = Update::new(&generations, |root_new: &Path| {
+                    fs::write(root_new.join("etc/count"), count.to_string())?;
+                    Ok(true)
+                })?;
+                for step in &steps[..count] {
+                    assert_eq!(update.next_step(), Some(*step));
+                    update.step()?;
+                }
+                let stopped = update.next_step();
+                assert_bootable(mount_dir, stopped);
+
+                if count == steps.len() {
+                    let generations = Generations::load(mount_dir, 0)?;
+                    let default = generations.default().unwrap();
+                    assert_eq!(default.path(), update.root());
+                    assert_eq!(
+                        fs::read_to_string(default.path().join("etc/count"))?,
+                        count.to_string()
+                    );
+                }
+            }
+
+            Ok(())
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use serde::Serialize;
+use std::{fmt, fs, io, path::Path};
+
+use crate::{
+    btrfs, parse_generation_name, util::format_time, with_top_dir, GenerationMetadata, Generations,
+    Pins,
+};
+
+/// The state of one root subvolume in the btrfs top level.
+#[derive(Debug, Serialize)]
+pub struct RootStatus {
+    pub name: String,
+    pub subvolid: u64,
+    /// Creation time, in seconds since the epoch.
+    pub created: Option<u64>,
+    pub readonly: bool,
+    pub booted: bool,
+    pub default: bool,
+    /// Pinned roots are never deleted.
+    pub pinned: bool,
+    pub kernel: Option<String>,
+    /// Why the root exists, if it was created by `pop-core run`.
+    pub metadata: Option<GenerationMetadata>,
+}
+
+/// The state of every root subvolume, as reported by `pop-core status`.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub roots: Vec<RootStatus>,
+    /// The default root differs from the booted root, so a reboot is needed to use it.
+    pub pending_reboot: bool,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<16} {:>8} {:<26} {:<3} {:<14} {:<24} COMMAND",
+            "NAME", "SUBVOLID", "CREATED", "RO", "STATE", "KERNEL"
+        )?;
+        for root in &self.roots {
+            let states: Vec<_> = [
+                (root.booted, "booted"),
+                (root.default, "default"),
+                (root.pinned, "pinned"),
+            ]
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| *name)
+            .collect();
+            let state = if states.is_empty() {
+                "-".to_string()
+            } else {
+                states.join(",")
+            };
+            let command = match root.metadata.as_ref().and_then(|x| x.run.as_ref()) {
+                Some(run) => {
+                    let mut command = run.command.clone();
+                    for arg in &run.args {
+                        command.push(' ');
+                        command.push_str(arg);
+                    }
+                    match &run.user {
+                        Some(user) => format!("{} ({})", command, user),
+                        None => command,
+                    }
+                }
+                None => "-".to_string(),
+            };
+            writeln!(
+                f,
+                "{:<16} {:>8} {:<26} {:<3} {:<14} {:<24} {}",
+                root.name,
+                root.subvolid,
+                root.created.map_or("-".to_string(), format_time),
+                if root.readonly { "yes" } else { "no" },
+                state,
+                root.kernel.as_deref().unwrap_or("-"),
+                command,
+            )?;
+        }
+        if self.pending_reboot {
+            writeln!(f, "A new default root is waiting for a reboot")?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds the kernel version a root boots, from the `/boot/vmlinuz` symlink.
+fn kernel_version(root_dir: &Path) -> Option<String> {
+    let target = fs::read_link(root_dir.join("boot/vmlinuz")).ok()?;
+    let file_name = target.file_name()?.to_str()?;
+    file_name.strip_prefix("vmlinuz-").map(|x| x.to_string())
+}
+
+fn status_with_top_dir(top_dir: &Path) -> io::Result<Status> {
+    // Status is read-only, so unlike other commands this does not migrate an older layout
+    let generations = Generations::load(top_dir, btrfs::subvolume_id("/")?)?;
+    let pins = Pins::load(top_dir)?;
+
+    let mut names = Vec::new();
+    for entry_res in fs::read_dir(top_dir)? {
+        let entry = entry_res?;
+        if let Some(name) = entry.file_name().to_str() {
+            if (name == "@root" || name.starts_with("@root.")) && entry.file_type()?.is_dir() {
+                names.push(name.to_string());
+            }
+        }
+    }
+    // Generations sort by number, everything else sorts by name after them
+    names.sort_by_key(|name| {
+        let number = parse_generation_name(name);
+        (number.is_none(), number, name.clone())
+    });
+
+    let mut roots = Vec::with_capacity(names.len());
+    for name in names {
+        let root_dir = top_dir.join(&name);
+        let info = btrfs::subvolume_info(&root_dir)?;
+        roots.push(RootStatus {
+            booted: info.id == generations.booted_subvolid(),
+            default: info.id == generations.default_subvolid(),
+            pinned: pins.contains(info.id),
+            kernel: kernel_version(&root_dir),
+            metadata: GenerationMetadata::read(&root_dir).unwrap_or_else(|err| {
+                log::warn!("{}", err);
+                None
+            }),
+            name,
+            subvolid: info.id,
+            created: info.created,
+            readonly: info.readonly,
+        });
+    }
+
+    Ok(Status {
+        roots,
+        pending_reboot: generations.default_subvolid() != generations.booted_subvolid(),
+    })
+}
+
+/// Reports the state of every root subvolume.
+pub fn status() -> io::Result<Status> {
+    with_top_dir(status_with_top_dir)
+}
@@ -1,4 +1,18 @@
-use std::{io, process};
+use std::{
+    env,
+    ffi::{CString, OsString},
+    io,
+    os::{
+        raw::{c_char, c_int},
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 pub fn check_output(output: process::Output) -> io::Result<process::Output> {
     check_status(output.status)?;
@@ -12,3 +26,155 @@ pub fn check_status(status: process::ExitStatus) -> io::Result<()> {
         Err(io::Error::new(io::ErrorKind::Other, format!("{}", status)))
     }
 }
+
+fn path_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Atomically renames `from` to `to`, failing with `AlreadyExists` instead of replacing `to`.
+pub fn rename_noreplace<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let c_from = path_cstring(from.as_ref())?;
+    let c_to = path_cstring(to.as_ref())?;
+    match unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            c_from.as_ptr(),
+            libc::AT_FDCWD,
+            c_to.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    } {
+        0 => Ok(()),
+        _err => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Atomically swaps the names of `a` and `b`, which must both exist.
+pub fn rename_exchange<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> io::Result<()> {
+    let c_a = path_cstring(a.as_ref())?;
+    let c_b = path_cstring(b.as_ref())?;
+    match unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            c_a.as_ptr(),
+            libc::AT_FDCWD,
+            c_b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    } {
+        0 => Ok(()),
+        _err => Err(io::Error::last_os_error()),
+    }
+}
+
+const INTERRUPT_SIGNALS: [c_int; 3] = [libc::SIGHUP, libc::SIGINT, libc::SIGTERM];
+
+/// Runs `command` and waits for it, ignoring interrupts in this process while it runs so that the
+/// caller can clean up after the command handles them. The command itself sees interrupts as
+/// usual.
+pub fn status_ignoring_interrupts(
+    command: &mut process::Command,
+) -> io::Result<process::ExitStatus> {
+    unsafe {
+        command.pre_exec(|| {
+            for signal in INTERRUPT_SIGNALS {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+            Ok(())
+        });
+    }
+
+    let mut previous = [libc::SIG_DFL; INTERRUPT_SIGNALS.len()];
+    for (signal, handler) in INTERRUPT_SIGNALS.iter().zip(previous.iter_mut()) {
+        *handler = unsafe { libc::signal(*signal, libc::SIG_IGN) };
+    }
+
+    let res = command.status();
+
+    for (signal, handler) in INTERRUPT_SIGNALS.iter().zip(previous.iter()) {
+        unsafe {
+            libc::signal(*signal, *handler);
+        }
+    }
+
+    res
+}
+
+/// Returns the space in bytes available to unprivileged users on the filesystem at `path`.
+pub fn free_space<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let c_path = path_cstring(path.as_ref())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Returns the current time in seconds since the epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Returns the name of the user with `uid`, from the password database.
+pub fn user_name(uid: u32) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as c_char; 4096];
+    let mut result = std::ptr::null_mut();
+    let err =
+        unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if err != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Formats seconds since the epoch as local time, like `2022-10-18 14:03:52 -0600`.
+pub fn format_time(secs: u64) -> String {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return secs.to_string();
+    }
+
+    let mut buf = [0u8; 64];
+    let len = unsafe {
+        libc::strftime(
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            b"%Y-%m-%d %H:%M:%S %z\0".as_ptr() as *const c_char,
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Creates a new directory only accessible by this user, like `/tmp/pop-core.a1B2c3`.
+pub fn create_temp_dir(prefix: &str) -> io::Result<PathBuf> {
+    let template = env::temp_dir().join(format!("{}.XXXXXX", prefix));
+    let mut bytes = path_cstring(&template)?.into_bytes_with_nul();
+    if unsafe { libc::mkdtemp(bytes.as_mut_ptr() as *mut c_char) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    bytes.pop();
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+/// Formats a size in bytes with binary units, like `1.5 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}